@@ -0,0 +1,269 @@
+//! The `cyb::llm::agent` loop: script functions offered to an LLM as tools.
+//!
+//! Tools are functions of the script currently running, looked up by name and
+//! described by their doc comment and the parameter names from the unit's
+//! debug info. The conversation goes on until the LLM answers without
+//! requesting tool calls. Requested calls run in a VM over the same unit and
+//! runtime context, so they draw from the run's instruction budget and have
+//! the script's permissions. Functions of the script already on the call
+//! stack, including the one running the agent, can't be its tools.
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use rune::runtime::{DebugArgs, Object, Value as VmValue, Vec as VmVec, VmResult};
+use rune::{vm_try, Hash, Vm};
+use serde::Deserialize;
+use serde_json::{json, Value as SerdeValue};
+
+use crate::helpers::{from_json, from_options, map_to_rune_value};
+use crate::host::{ChatMessage, ChatOptions, ChatRole, ToolCall, ToolSpec};
+use crate::llm;
+use crate::session::{self, Program, Session};
+use crate::types::{AgentResponse, Usage};
+
+/// Default largest number of rounds of tool calls before the LLM answers.
+const MAX_STEPS: usize = 8;
+
+/// Largest number of rounds of tool calls a script can allow.
+const MAX_STEPS_LIMIT: usize = 32;
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct AgentOptions {
+    max_steps: Option<usize>,
+}
+
+/// An answer of the LLM, requesting tool calls or with content. Fields the
+/// host leaves out or sets to null are empty.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Turn {
+    content: Option<String>,
+    #[serde(alias = "toolCalls")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(alias = "finishReason")]
+    finish_reason: Option<String>,
+    model: Option<String>,
+    usage: Option<Usage>,
+}
+
+/// Look up the function `name` of the running script, which can't be one of
+/// `running`, the functions of the script on the call stack.
+fn tool(program: &Program, running: &[Hash], name: &str) -> VmResult<(Hash, ToolSpec)> {
+    let hash = Hash::type_hash(name.split("::"));
+
+    if program.unit.function(hash).is_none() {
+        return VmResult::panic(format!("llm::agent: `{}` is not a function of this script", name));
+    }
+
+    if running.contains(&hash) {
+        return VmResult::panic(format!("llm::agent: `{}` is running the agent and can't be a tool", name));
+    }
+
+    let signature = match program.unit.debug_info().and_then(|debug| debug.functions.get(&hash)) {
+        Some(signature) => signature,
+        None => return VmResult::panic(format!("llm::agent: `{}` has no debug info", name)),
+    };
+
+    let parameters = match &signature.args {
+        DebugArgs::EmptyArgs => Vec::new(),
+        DebugArgs::TupleArgs(count) => (0..*count).map(|i| format!("arg{}", i)).collect(),
+        DebugArgs::Named(names) => names.iter().map(|name| name.to_string()).collect(),
+    };
+
+    let spec = ToolSpec {
+        name: name.to_owned(),
+        description: program.docs.get(name).cloned().unwrap_or_default(),
+        parameters,
+    };
+
+    VmResult::Ok((hash, spec))
+}
+
+/// Call a tool with the arguments requested by the LLM, resolving to its
+/// result as JSON. It runs on the call stack so that it can't run an agent
+/// offering itself as a tool.
+///
+/// LLM APIs commonly send the arguments as a string holding a JSON object,
+/// which is parsed first.
+async fn call(
+    session: &Rc<Session>,
+    program: &Program,
+    hash: Hash,
+    spec: &ToolSpec,
+    arguments: &SerdeValue,
+) -> VmResult<SerdeValue> {
+    let parsed = match arguments {
+        SerdeValue::String(text) => serde_json::from_str(text).ok(),
+        _ => None,
+    };
+
+    let arguments = match &parsed {
+        Some(parsed @ (SerdeValue::Object(_) | SerdeValue::Array(_))) => parsed,
+        _ => arguments,
+    };
+
+    let args = match arguments {
+        SerdeValue::Object(arguments) => spec
+            .parameters
+            .iter()
+            .map(|name| arguments.get(name).map_or(VmValue::Unit, map_to_rune_value))
+            .collect(),
+        SerdeValue::Array(arguments) => arguments.iter().map(map_to_rune_value).collect(),
+        SerdeValue::Null => Vec::new(),
+        argument => vec![map_to_rune_value(argument)],
+    };
+
+    let mut vm = Vm::new(program.runtime.clone(), program.unit.clone());
+    let mut execution = vm_try!(vm.execute(hash, args));
+    let frames = session.enter_tool(&spec.name);
+
+    let value = match session::with_frames(session.clone(), frames, execution.async_complete()).await {
+        VmResult::Ok(value) => value,
+        VmResult::Err(error) => return VmResult::panic(format!("tool `{}` failed: {}", spec.name, error)),
+    };
+
+    match serde_json::to_value(&value) {
+        Ok(value) => VmResult::Ok(value),
+        Err(_) => VmResult::Ok(SerdeValue::String(format!("{:?}", value))),
+    }
+}
+
+/// Ask the LLM for its next turn, adding it to `messages` and its usage to
+/// `usage`.
+async fn next_turn(
+    session: &Session,
+    messages: &mut Vec<ChatMessage>,
+    specs: &[ToolSpec],
+    chat_options: &ChatOptions,
+    usage: &mut Usage,
+) -> VmResult<Turn> {
+    let args = json!([messages, specs, chat_options]);
+    let result = vm_try!(session.call_json("llm_chat_tools", args, |host| host.llm_chat_tools(&messages[..], specs, chat_options)).await);
+
+    let turn: Turn = match result {
+        SerdeValue::Null => return VmResult::panic("llm::agent: the host returned no response"),
+        SerdeValue::String(content) => Turn {
+            content: Some(content),
+            ..Turn::default()
+        },
+        result => vm_try!(from_json("llm::agent", result)),
+    };
+
+    if turn.tool_calls.as_ref().map_or(true, Vec::is_empty) && turn.content.is_none() {
+        return VmResult::panic("llm::agent: the host returned neither content nor tool calls");
+    }
+
+    if let Some(turn_usage) = &turn.usage {
+        usage.prompt_tokens += turn_usage.prompt_tokens;
+        usage.completion_tokens += turn_usage.completion_tokens;
+        usage.total_tokens += turn_usage.total_tokens;
+    }
+
+    messages.push(ChatMessage {
+        role: ChatRole::Assistant,
+        content: turn.content.clone().unwrap_or_default(),
+        tool_calls: turn.tool_calls.clone().unwrap_or_default(),
+        tool_call_id: None,
+    });
+
+    VmResult::Ok(turn)
+}
+
+/// The response to a turn of the LLM that requested no tool calls.
+fn answer(turn: Turn, usage: Usage, tool_calls: i64, messages: &[ChatMessage]) -> AgentResponse {
+    AgentResponse {
+        content: turn.content.unwrap_or_default(),
+        finish_reason: turn.finish_reason,
+        model: turn.model,
+        usage,
+        tool_calls,
+        messages: serde_json::to_value(messages).unwrap_or_default(),
+    }
+}
+
+async fn run(messages: VmVec, tools: VmVec, options: Object) -> VmResult<AgentResponse> {
+    let (mut messages, chat_options) = vm_try!(llm::request("llm::agent", &messages, &options));
+    let options: AgentOptions = vm_try!(from_options("llm::agent", &options));
+    let names: Vec<String> = vm_try!(from_options("llm::agent", &tools));
+    let max_steps = options.max_steps.unwrap_or(MAX_STEPS);
+
+    if max_steps > MAX_STEPS_LIMIT {
+        return VmResult::panic(format!("llm::agent: max_steps is limited to {}", MAX_STEPS_LIMIT));
+    }
+
+    let session = vm_try!(session::current());
+
+    let program = match session.program() {
+        Some(program) => program,
+        None => return VmResult::panic("llm::agent: no script is running"),
+    };
+
+    let running = session
+        .call_stack()
+        .iter()
+        .filter(|frame| frame.program.as_ref().map_or(false, |p| Arc::ptr_eq(&p.unit, &program.unit)))
+        .map(|frame| Hash::type_hash(frame.func_name.split("::")))
+        .collect::<Vec<_>>();
+
+    let mut hashes = Vec::new();
+    let mut specs = Vec::new();
+
+    for name in &names {
+        let (hash, spec) = vm_try!(tool(&program, &running, name));
+        hashes.push(hash);
+        specs.push(spec);
+    }
+
+    let mut usage = Usage::default();
+    let mut tool_calls = 0;
+
+    for _ in 0..max_steps {
+        let turn = vm_try!(next_turn(&session, &mut messages, &specs, &chat_options, &mut usage).await);
+
+        let requests = match &turn.tool_calls {
+            Some(requests) if !requests.is_empty() => requests.clone(),
+            _ => return VmResult::Ok(answer(turn, usage, tool_calls, &messages)),
+        };
+
+        for request in requests {
+            // NB: unknown tools are reported back so the LLM can correct
+            // itself, failing tools fail the script.
+            let content = match specs.iter().position(|spec| spec.name == request.name) {
+                Some(index) => vm_try!(call(&session, &program, hashes[index], &specs[index], &request.arguments).await),
+                None => json!({ "error": format!("unknown tool `{}`", request.name) }),
+            };
+
+            tool_calls += 1;
+
+            messages.push(ChatMessage {
+                role: ChatRole::Tool,
+                content: content.to_string(),
+                tool_calls: Vec::new(),
+                tool_call_id: Some(request.id),
+            });
+        }
+    }
+
+    // NB: the budget is used up, tool calls requested now aren't run.
+    let turn = vm_try!(next_turn(&session, &mut messages, &specs, &chat_options, &mut usage).await);
+
+    if turn.tool_calls.as_ref().map_or(true, Vec::is_empty) {
+        return VmResult::Ok(answer(turn, usage, tool_calls, &messages));
+    }
+
+    VmResult::panic(format!("llm::agent: no answer after {} rounds of tool calls", max_steps))
+}
+
+/// Continue the chat in `messages`, letting the LLM call the functions of this
+/// script named in `tools` until it answers.
+pub(crate) async fn agent(messages: VmVec, tools: VmVec) -> VmResult<AgentResponse> {
+    run(messages, tools, Object::new()).await
+}
+
+/// Like `agent` with `options` being those of `chat` plus `max_steps`, the
+/// largest number of rounds of tool calls, at most 32.
+pub(crate) async fn agent_with(messages: VmVec, tools: VmVec, options: Object) -> VmResult<AgentResponse> {
+    run(messages, tools, options).await
+}
@@ -0,0 +1,179 @@
+//! Run a cyb Rune script from the terminal.
+//!
+//! Host calls are served by an in-memory host loaded from a JSON fixture, see
+//! [cyb_rune_wasm::host::MemoryHost] for its format.
+
+use std::fs;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use anyhow::{bail, Context as _};
+use cyb_rune_wasm::host::MemoryHost;
+use cyb_rune_wasm::CompilerParams;
+use serde_json::{json, Value as SerdeValue};
+
+const USAGE: &str = "\
+Usage: cyb-rune <script> [options]
+
+Options:
+  --scripts <file>     Additional sources to compile with the script
+  --fixture <file>     JSON fixture for the in-memory host
+  --func <name>        Function to execute (default: main)
+  --params <json>      Parameters to call the function with
+  --context <json>     Run params, exposed to the script as `cyb::context::*`
+  --budget <n>         Instruction budget
+  --option <option>    Compiler option, can be repeated
+  --experimental       Include the `std::experiments` package
+  --instructions       Dump instructions
+  --read-only          Leave out functions writing to the graph
+  --script-id <id>     Identity of the script, scoping `cyb::storage`
+  --check              Only compile the script";
+
+struct Args {
+    script: String,
+    scripts: Option<String>,
+    fixture: Option<String>,
+    params: SerdeValue,
+    compiler_params: SerdeValue,
+}
+
+/// Parse the command line, `None` if usage was asked for.
+fn parse_args() -> anyhow::Result<Option<Args>> {
+    let mut args = std::env::args().skip(1);
+
+    let mut script = None;
+    let mut scripts = None;
+    let mut fixture = None;
+    let mut params = json!({});
+    let mut func_name = String::from("main");
+    let mut func_params = json!([]);
+    let mut config = json!({});
+    let mut options = Vec::new();
+    let mut read_only = false;
+    let mut script_id = None;
+    let mut execute = true;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("{} requires a value", arg));
+
+        match arg.as_str() {
+            "--scripts" => scripts = Some(value()?),
+            "--fixture" => fixture = Some(value()?),
+            "--func" => func_name = value()?,
+            "--params" => func_params = serde_json::from_str(&value()?).context("parsing --params")?,
+            "--context" => params = serde_json::from_str(&value()?).context("parsing --context")?,
+            "--budget" => config["budget"] = json!(value()?.parse::<usize>().context("parsing --budget")?),
+            "--option" => options.push(value()?),
+            "--experimental" => config["experimental"] = json!(true),
+            "--instructions" => config["instructions"] = json!(true),
+            "--read-only" => read_only = true,
+            "--script-id" => script_id = Some(value()?),
+            "--check" => execute = false,
+            "-h" | "--help" => return Ok(None),
+            _ if arg.starts_with("--") => bail!("unknown option {}\n\n{}", arg, USAGE),
+            _ if script.is_none() => script = Some(arg),
+            _ => bail!("unexpected argument {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let Some(script) = script else {
+        bail!("{}", USAGE);
+    };
+
+    config["options"] = json!(options);
+
+    let compiler_params = json!({
+        "readOnly": read_only,
+        "scriptId": script_id,
+        "funcName": func_name,
+        "funcParams": func_params,
+        "execute": execute,
+        "config": config,
+    });
+
+    Ok(Some(Args {
+        script,
+        scripts,
+        fixture,
+        params,
+        compiler_params,
+    }))
+}
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("{:?}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn try_main() -> anyhow::Result<ExitCode> {
+    let Some(args) = parse_args()? else {
+        println!("{}", USAGE);
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    let input = fs::read_to_string(&args.script)
+        .with_context(|| format!("reading {}", args.script))?;
+
+    let scripts = match &args.scripts {
+        Some(path) => fs::read_to_string(path).with_context(|| format!("reading {}", path))?,
+        None => String::new(),
+    };
+
+    let host = match &args.fixture {
+        Some(path) => {
+            let fixture = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+            serde_json::from_str(&fixture).with_context(|| format!("parsing fixture {}", path))?
+        }
+        None => MemoryHost::new(),
+    };
+
+    let compiler_params: CompilerParams = serde_json::from_value(args.compiler_params)?;
+
+    // NB: the `http` module needs a tokio runtime to run its requests on.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("starting runtime")?;
+
+    let host = Rc::new(host);
+
+    let result = runtime.block_on(cyb_rune_wasm::run(
+        input,
+        scripts,
+        args.params,
+        compiler_params,
+        host.clone(),
+    ));
+
+    for message in host.logs() {
+        eprintln!("log: {}", message);
+    }
+
+    if let Some(instructions) = result.instructions() {
+        println!("{}", instructions);
+    }
+
+    if let Some(output) = result.output().filter(|output| !output.is_empty()) {
+        print!("{}", output);
+    }
+
+    if let Some(diagnostics) = result.diagnostics_output().filter(|d| !d.is_empty()) {
+        eprintln!("{}", diagnostics);
+    }
+
+    if let Some(error) = result.error() {
+        eprintln!("error: {}", error);
+        return Ok(ExitCode::FAILURE);
+    }
+
+    if let Some(value) = result.result() {
+        println!("== {}", value);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,166 @@
+//! Precompiled units.
+//!
+//! A unit can be compiled once, stored as bytes (e.g. as a particle next to
+//! its source) and loaded later without running the compiler. The bytes carry
+//! a header with the format version, the crate version, the cache key of the
+//! sources and settings the unit was compiled from and the SHA-256 digest of
+//! the serialized unit. The header is signed with an HMAC-SHA256 key held by
+//! the app, so a unit is only loaded if it was produced with the same key and
+//! the header matches the run; otherwise the caller compiles from source.
+//!
+//! The doc comments of the unit's functions are stored along with it.
+
+use anyhow::{bail, Context as _};
+use hmac::{Hmac, Mac};
+use rune::Unit;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cache::Key;
+use crate::docs::Docs;
+
+/// Version of the precompiled unit format.
+pub(crate) const BYTECODE_VERSION: u32 = 3;
+
+/// Shortest signing key accepted.
+const MIN_SIGNING_KEY: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    crate_version: String,
+    key: Key,
+    /// SHA-256 of the serialized body.
+    digest: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    header: Header,
+    /// HMAC-SHA256 of the serialized header.
+    mac: Vec<u8>,
+    /// The serialized [Body].
+    body: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct BodyRef<'a> {
+    unit: &'a Unit,
+    docs: &'a Docs,
+}
+
+#[derive(Deserialize)]
+struct Body {
+    unit: Unit,
+    docs: Docs,
+}
+
+fn mac(signing_key: &[u8], header: &Header) -> anyhow::Result<Hmac<Sha256>> {
+    if signing_key.len() < MIN_SIGNING_KEY {
+        bail!("signing key must be at least {} bytes", MIN_SIGNING_KEY);
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key).context("invalid signing key")?;
+    mac.update(&bincode::serialize(header).context("serializing header")?);
+    Ok(mac)
+}
+
+/// Serialize `unit` with its `docs`, compiled from the sources and settings
+/// hashed into `key`, and sign it with `signing_key`.
+pub(crate) fn save(key: Key, unit: &Unit, docs: &Docs, signing_key: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let body = bincode::serialize(&BodyRef { unit, docs }).context("serializing unit")?;
+
+    let header = Header {
+        version: BYTECODE_VERSION,
+        crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+        key,
+        digest: Sha256::digest(&body).into(),
+    };
+
+    let mac = mac(signing_key, &header)?.finalize().into_bytes().to_vec();
+    bincode::serialize(&Envelope { header, mac, body }).context("serializing envelope")
+}
+
+/// Deserialize a unit and its docs, failing with the reason if it wasn't
+/// signed with `signing_key` or compiled by this version of the crate from
+/// the sources and settings hashed into `key`.
+pub(crate) fn load(bytes: &[u8], key: &Key, signing_key: &[u8]) -> anyhow::Result<(Unit, Docs)> {
+    // NB: the unit is only deserialized once the envelope is authenticated,
+    // so forged or foreign bytes never reach the deserializer of the unit.
+    let envelope: Envelope = bincode::deserialize(bytes).context("malformed envelope")?;
+    let header = &envelope.header;
+
+    mac(signing_key, header)?
+        .verify_slice(&envelope.mac)
+        .map_err(|_| anyhow::anyhow!("not signed with this signing key"))?;
+
+    if header.version != BYTECODE_VERSION {
+        bail!("unsupported format version {}", header.version);
+    }
+
+    if header.crate_version != env!("CARGO_PKG_VERSION") {
+        bail!("compiled by version {}", header.crate_version);
+    }
+
+    if header.key != *key {
+        bail!("compiled from different sources or settings");
+    }
+
+    if <[u8; 32]>::from(Sha256::digest(&envelope.body)) != header.digest {
+        bail!("unit doesn't match its digest");
+    }
+
+    let body: Body = bincode::deserialize(&envelope.body).context("malformed unit")?;
+    Ok((body.unit, body.docs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNING_KEY: &[u8] = b"0123456789abcdef";
+
+    fn unit() -> Unit {
+        let mut sources = rune::sources!(entry => {
+            pub fn main() { 42 }
+        });
+
+        rune::prepare(&mut sources).build().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = save([1; 32], &unit(), &Docs::new(), SIGNING_KEY).unwrap();
+        assert!(load(&bytes, &[1; 32], SIGNING_KEY).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_key_or_signing_key() {
+        let bytes = save([1; 32], &unit(), &Docs::new(), SIGNING_KEY).unwrap();
+        assert!(load(&bytes, &[2; 32], SIGNING_KEY).is_err());
+        assert!(load(&bytes, &[1; 32], b"fedcba9876543210").is_err());
+    }
+
+    #[test]
+    fn rejects_short_signing_key() {
+        assert!(save([1; 32], &unit(), &Docs::new(), b"short").is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let bytes = save([1; 32], &unit(), &Docs::new(), SIGNING_KEY).unwrap();
+        let mut envelope: Envelope = bincode::deserialize(&bytes).unwrap();
+        envelope.body.push(0);
+        let bytes = bincode::serialize(&envelope).unwrap();
+        assert!(load(&bytes, &[1; 32], SIGNING_KEY).is_err());
+    }
+
+    #[test]
+    fn rejects_forged_header() {
+        let bytes = save([1; 32], &unit(), &Docs::new(), SIGNING_KEY).unwrap();
+        let mut envelope: Envelope = bincode::deserialize(&bytes).unwrap();
+        envelope.header.key = [2; 32];
+        let bytes = bincode::serialize(&envelope).unwrap();
+        assert!(load(&bytes, &[2; 32], SIGNING_KEY).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Cache of compiled units.
+//!
+//! The same script is often run many times in a row, e.g. once for every
+//! particle of a feed, and compiling it dominates the cost of short runs.
+//! Units are kept in a small LRU cache keyed by a hash of everything that
+//! affects compilation: the sources, the compiler options and the shape of
+//! the context they are compiled against.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use rune::Unit;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::context::RunContext;
+use crate::docs::Docs;
+use crate::extensions;
+use crate::WasmDiagnostic;
+
+/// Number of units kept before the least recently used one is evicted.
+const CAPACITY: usize = 64;
+
+thread_local! {
+    static CACHE: RefCell<UnitCache> = RefCell::new(UnitCache::default());
+}
+
+/// Hash identifying a compiled unit.
+///
+/// It is stable across builds and platforms, so it can also be stored along
+/// with a precompiled unit.
+pub(crate) type Key = [u8; 32];
+
+/// A compiled unit along with the diagnostics emitted while compiling it.
+#[derive(Clone)]
+pub(crate) struct CachedUnit {
+    pub(crate) unit: Arc<Unit>,
+    /// Doc comments of its functions.
+    pub(crate) docs: Arc<Docs>,
+    /// Positioned diagnostics reported to the editor.
+    pub(crate) diagnostics: Vec<WasmDiagnostic>,
+    /// Diagnostics rendered as text.
+    pub(crate) rendered: Vec<u8>,
+}
+
+/// Counters of the cache since it was created.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    size: usize,
+    capacity: usize,
+}
+
+impl CacheStats {
+    /// Units found in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Units compiled because they weren't in the cache.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Units dropped to make room for others.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Units currently cached.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Units kept before evicting.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[derive(Default)]
+struct UnitCache {
+    /// Entries from least to most recently used.
+    entries: Vec<(Key, CachedUnit)>,
+    stats: CacheStats,
+}
+
+/// Hash everything compiling `sources` depends on.
+///
+/// Besides the options this covers what the context is built from: the
+/// permissions, the run context compiled into the `cyb::context` constants
+/// and the registered functions.
+pub(crate) fn key(
+    sources: &[&str],
+    options: &[String],
+    experimental: bool,
+    read_only: bool,
+    sealed: bool,
+    params: &RunContext,
+) -> Key {
+    let mut hasher = Sha256::new();
+
+    for source in sources {
+        update(&mut hasher, source.as_bytes());
+    }
+
+    for option in options {
+        update(&mut hasher, option.as_bytes());
+    }
+
+    update(&mut hasher, &[experimental as u8, read_only as u8, sealed as u8]);
+    let params = serde_json::to_vec(params).unwrap_or_default();
+    update(&mut hasher, &params);
+
+    let descriptors = serde_json::to_vec(&extensions::descriptors()).unwrap_or_default();
+    update(&mut hasher, &descriptors);
+
+    hasher.finalize().into()
+}
+
+/// Feed a length-prefixed field to the hasher, so that fields can't run
+/// into each other.
+fn update(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// Look up a unit, marking it as the most recently used.
+pub(crate) fn get(key: &Key) -> Option<CachedUnit> {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        match cache.entries.iter().position(|(k, _)| k == key) {
+            Some(index) => {
+                let entry = cache.entries.remove(index);
+                let unit = entry.1.clone();
+                cache.entries.push(entry);
+                cache.stats.hits += 1;
+                Some(unit)
+            }
+            None => {
+                cache.stats.misses += 1;
+                None
+            }
+        }
+    })
+}
+
+/// Store a unit, evicting the least recently used one if the cache is full.
+pub(crate) fn insert(key: Key, unit: CachedUnit) {
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        cache.entries.retain(|(k, _)| *k != key);
+        cache.entries.push((key, unit));
+
+        if cache.entries.len() > CAPACITY {
+            cache.entries.remove(0);
+            cache.stats.evictions += 1;
+        }
+    })
+}
+
+/// Drop every cached unit.
+pub(crate) fn clear() {
+    CACHE.with(|cache| cache.borrow_mut().entries.clear())
+}
+
+pub(crate) fn stats() -> CacheStats {
+    CACHE.with(|cache| {
+        let cache = cache.borrow();
+
+        CacheStats {
+            size: cache.entries.len(),
+            capacity: CAPACITY,
+            ..cache.stats
+        }
+    })
+}
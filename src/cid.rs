@@ -0,0 +1,424 @@
+//! Local CID computation and parsing.
+//!
+//! `cid_of` computes the CIDv0 IPFS assigns to content added with its default
+//! settings: 256 KiB chunks laid out as a balanced tree of dag-pb UnixFS
+//! nodes with at most 174 links each. `cid_v1_of` computes the CIDv1 assigned
+//! when adding with `--cid-version 1`, which also turns on raw leaves.
+
+use rune::runtime::{Value as VmValue, VmResult};
+use rune::{vm_try, Any, ContextError, Module};
+use sha2::{Digest, Sha256};
+
+/// Size of the chunks content is split into.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest number of links of a node.
+const MAX_LINKS: usize = 174;
+
+/// Multihash code of sha2-256.
+const SHA2_256: u64 = 0x12;
+
+/// Multicodec of raw blocks.
+const RAW: u64 = 0x55;
+
+/// Multicodec of dag-pb blocks.
+const DAG_PB: u64 = 0x70;
+
+/// UnixFS type of file nodes.
+const UNIXFS_FILE: u64 = 2;
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// A parsed CID.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct Cid {
+    /// CID version, 0 or 1.
+    #[rune(get, copy)]
+    version: i64,
+    /// Name of the content codec, e.g. `dag-pb` or `raw`, or its hex code if
+    /// unknown.
+    #[rune(get)]
+    codec: String,
+    /// Name of the hash function, e.g. `sha2-256`, or its hex code if
+    /// unknown.
+    #[rune(get)]
+    hash: String,
+    /// Hex-encoded digest of the content.
+    #[rune(get)]
+    digest: String,
+}
+
+/// A block linked from a dag-pb node.
+struct Block {
+    /// Binary CID of the block.
+    cid: Vec<u8>,
+    /// Size of the block along with everything it links to.
+    tsize: u64,
+    /// Size of the file content under the block.
+    filesize: u64,
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Append a length-delimited protobuf field.
+fn field_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Append a varint protobuf field.
+fn field_varint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    varint(buf, field << 3);
+    varint(buf, value);
+}
+
+fn multihash(block: &[u8]) -> Vec<u8> {
+    let mut multihash = vec![SHA2_256 as u8, 32];
+    multihash.extend_from_slice(&Sha256::digest(block));
+    multihash
+}
+
+/// Binary CID of a block, CIDv0 being the bare multihash.
+fn block_cid(version: u64, codec: u64, block: &[u8]) -> Vec<u8> {
+    if version == 0 {
+        return multihash(block);
+    }
+
+    let mut cid = Vec::new();
+    varint(&mut cid, 1);
+    varint(&mut cid, codec);
+    cid.extend(multihash(block));
+    cid
+}
+
+/// Encode a dag-pb node, links going before data as in canonical dag-pb.
+fn dag_pb(links: &[Block], data: &[u8]) -> Vec<u8> {
+    let mut node = Vec::new();
+
+    for link in links {
+        let mut encoded = Vec::new();
+        field_bytes(&mut encoded, 1, &link.cid);
+        field_bytes(&mut encoded, 2, b"");
+        field_varint(&mut encoded, 3, link.tsize);
+        field_bytes(&mut node, 2, &encoded);
+    }
+
+    field_bytes(&mut node, 1, data);
+    node
+}
+
+/// Encode UnixFS file metadata.
+fn unixfs_file(data: &[u8], filesize: u64, blocksizes: &[u64]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    field_varint(&mut encoded, 1, UNIXFS_FILE);
+
+    if !data.is_empty() {
+        field_bytes(&mut encoded, 2, data);
+    }
+
+    field_varint(&mut encoded, 3, filesize);
+
+    for size in blocksizes {
+        field_varint(&mut encoded, 4, *size);
+    }
+
+    encoded
+}
+
+fn leaf(version: u64, chunk: &[u8]) -> Block {
+    if version == 1 {
+        return Block {
+            cid: block_cid(1, RAW, chunk),
+            tsize: chunk.len() as u64,
+            filesize: chunk.len() as u64,
+        };
+    }
+
+    let node = dag_pb(&[], &unixfs_file(chunk, chunk.len() as u64, &[]));
+
+    Block {
+        cid: block_cid(0, DAG_PB, &node),
+        tsize: node.len() as u64,
+        filesize: chunk.len() as u64,
+    }
+}
+
+fn parent(version: u64, children: Vec<Block>) -> Block {
+    let filesize = children.iter().map(|child| child.filesize).sum();
+    let blocksizes = children.iter().map(|child| child.filesize).collect::<Vec<_>>();
+    let node = dag_pb(&children, &unixfs_file(&[], filesize, &blocksizes));
+
+    Block {
+        cid: block_cid(version, DAG_PB, &node),
+        tsize: node.len() as u64 + children.iter().map(|child| child.tsize).sum::<u64>(),
+        filesize,
+    }
+}
+
+/// Binary CID of `content` laid out as IPFS does.
+fn file_cid(version: u64, content: &[u8]) -> Vec<u8> {
+    if content.len() <= CHUNK_SIZE {
+        return leaf(version, content).cid;
+    }
+
+    let mut level = content
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| leaf(version, chunk))
+        .collect::<Vec<_>>();
+
+    loop {
+        let mut parents = Vec::new();
+        let mut level_iter = level.into_iter().peekable();
+
+        while level_iter.peek().is_some() {
+            let children = level_iter.by_ref().take(MAX_LINKS).collect();
+            parents.push(parent(version, children));
+        }
+
+        if parents.len() == 1 {
+            return parents.remove(0).cid;
+        }
+
+        level = parents;
+    }
+}
+
+fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = buffer << 8 | u32::from(byte);
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+
+    encoded
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_lowercase())? as u32;
+        buffer = buffer << 5 | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Text form of a binary CID, base58btc for CIDv0 and base32 otherwise.
+fn to_string(cid: &[u8]) -> String {
+    if cid.first() == Some(&(SHA2_256 as u8)) {
+        bs58::encode(cid).into_string()
+    } else {
+        format!("b{}", base32(cid))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn codec_name(codec: u64) -> String {
+    match codec {
+        RAW => "raw".to_owned(),
+        DAG_PB => "dag-pb".to_owned(),
+        0x71 => "dag-cbor".to_owned(),
+        0x0129 => "dag-json".to_owned(),
+        codec => format!("0x{:x}", codec),
+    }
+}
+
+/// Parse the text form of a CID.
+fn parse(text: &str) -> Option<Cid> {
+    let (version, bytes) = if text.len() == 46 && text.starts_with("Qm") {
+        (0, bs58::decode(text).into_vec().ok()?)
+    } else {
+        let bytes = if let Some(rest) = text.strip_prefix('b') {
+            base32_decode(rest)?
+        } else if let Some(rest) = text.strip_prefix('z') {
+            bs58::decode(rest).into_vec().ok()?
+        } else {
+            return None;
+        };
+
+        (1, bytes)
+    };
+
+    let mut rest = bytes.as_slice();
+
+    let codec = if version == 0 {
+        DAG_PB
+    } else {
+        if read_varint(&mut rest)? != 1 {
+            return None;
+        }
+
+        read_varint(&mut rest)?
+    };
+
+    let hash = read_varint(&mut rest)?;
+    let length = read_varint(&mut rest)?;
+
+    if rest.len() as u64 != length || (hash == SHA2_256 && length != 32) {
+        return None;
+    }
+
+    Some(Cid {
+        version,
+        codec: codec_name(codec),
+        hash: match hash {
+            SHA2_256 => "sha2-256".to_owned(),
+            hash => format!("0x{:x}", hash),
+        },
+        digest: hex(rest),
+    })
+}
+
+/// The bytes of a string or bytes value.
+fn content(value: VmValue) -> VmResult<Vec<u8>> {
+    if let VmValue::Bytes(bytes) = &value {
+        return VmResult::Ok(vm_try!(bytes.borrow_ref()).to_vec());
+    }
+
+    match rune::from_value::<String>(value) {
+        Ok(text) => VmResult::Ok(text.into_bytes()),
+        Err(_) => VmResult::panic("expected a string or bytes"),
+    }
+}
+
+/// The CIDv0 IPFS assigns to `content` by default.
+fn cid_of(content: VmValue) -> VmResult<String> {
+    let content = vm_try!(self::content(content));
+    VmResult::Ok(to_string(&file_cid(0, &content)))
+}
+
+/// The CIDv1 IPFS assigns to `content` when adding with `--cid-version 1`.
+fn cid_v1_of(content: VmValue) -> VmResult<String> {
+    let content = vm_try!(self::content(content));
+    VmResult::Ok(to_string(&file_cid(1, &content)))
+}
+
+/// Test if `text` is a valid CID.
+pub(crate) fn is_cid(text: &str) -> bool {
+    parse(text).is_some()
+}
+
+/// Parse `text` as a CID, `None` if it isn't one.
+fn parse_cid(text: &str) -> Option<Cid> {
+    parse(text)
+}
+
+/// The CIDv0 IPFS assigns to `content` by default.
+pub(crate) fn of(content: &[u8]) -> String {
+    to_string(&file_cid(0, content))
+}
+
+/// Install the CID functions into the `cyb` module.
+pub(crate) fn install(module: &mut Module) -> Result<(), ContextError> {
+    module.ty::<Cid>()?;
+    module.function(["cid_of"], cid_of)?;
+    module.function(["cid_v1_of"], cid_v1_of)?;
+    module.function(["is_cid"], is_cid)?;
+    module.function(["parse_cid"], parse_cid)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v1(content: &[u8]) -> String {
+        to_string(&file_cid(1, content))
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(of(b""), "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH");
+        assert_eq!(v1(b""), "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku");
+    }
+
+    #[test]
+    fn hello_world() {
+        assert_eq!(of(b"hello world"), "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD");
+        assert_eq!(v1(b"hello world"), "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e");
+    }
+
+    #[test]
+    fn chunked() {
+        let content = vec![b'a'; CHUNK_SIZE + 1];
+        assert_eq!(of(&content), "QmTaxvXcxpzzaatSEEAYr7t3knkJ6DmTVbr8MjJJWLRWpV");
+        assert_eq!(v1(&content), "bafybeifwp2ckiq7kaefcrxkcalv6bs5x7e7ozrigy4ypvcqwetgnxfb2hi");
+
+        let cid = parse(&v1(&content)).unwrap();
+        assert_eq!(cid.codec, "dag-pb");
+    }
+
+    #[test]
+    fn parse_v0() {
+        let cid = parse("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH").unwrap();
+        assert_eq!(cid.version, 0);
+        assert_eq!(cid.codec, "dag-pb");
+        assert_eq!(cid.hash, "sha2-256");
+    }
+
+    #[test]
+    fn parse_v1() {
+        let cid = parse("bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku").unwrap();
+        assert_eq!(cid.version, 1);
+        assert_eq!(cid.codec, "raw");
+        assert_eq!(cid.digest, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse("").is_none());
+        assert!(parse("é").is_none());
+        assert!(parse("ébafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku").is_none());
+        assert!(parse("bé").is_none());
+        assert!(parse("b").is_none());
+        assert!(parse("hello world").is_none());
+    }
+}
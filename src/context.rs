@@ -0,0 +1,104 @@
+//! The run context exposed to scripts as `cyb::context::*` constants.
+//!
+//! The `params` of a run are validated against [RunContext] before anything
+//! is compiled, so scripts can rely on the shape of every namespace. Fields
+//! outside of it are ignored with a warning.
+//!
+//! The constants are compiled into the unit, so compiled units are cached
+//! per run context.
+
+use anyhow::{bail, Context as _};
+use rune::runtime::Value as VmValue;
+use rune::{ContextError, Module};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as SerdeValue;
+
+use crate::cid;
+use crate::helpers::map_to_rune_value;
+
+/// The neuron the script runs for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct UserContext {
+    /// Address of the neuron.
+    address: String,
+    /// Nickname of its passport, if it has one.
+    #[serde(default)]
+    nickname: Option<String>,
+}
+
+/// The app running the script.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct EnvContext {
+    /// Version of the app.
+    app_version: Option<String>,
+    /// Locale of the user interface, e.g. `en-US`.
+    locale: Option<String>,
+    /// Network the app is connected to, e.g. `bostrom`.
+    network: Option<String>,
+}
+
+/// Parameters of a run.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct RunContext {
+    /// The neuron the script runs for, `cyb::context::user`.
+    user: Option<UserContext>,
+    /// CID of the particle being viewed, `cyb::context::particle`.
+    particle: Option<String>,
+    /// The app running the script, `cyb::context::env`.
+    env: EnvContext,
+    /// Free-form app data, `cyb::context::app` and `cyb::context`.
+    app: SerdeValue,
+}
+
+impl RunContext {
+    /// Validate the `params` of a run, along with warnings about the fields
+    /// that aren't part of the schema and are ignored.
+    pub(crate) fn parse(params: &SerdeValue) -> anyhow::Result<(Self, Vec<String>)> {
+        if params.is_null() {
+            return Ok((Self::default(), Vec::new()));
+        }
+
+        let context = Self::deserialize(params).context("invalid run params")?;
+
+        if let Some(particle) = &context.particle {
+            if !cid::is_cid(particle) {
+                bail!("invalid run params: particle `{}` is not a CID", particle);
+            }
+        }
+
+        let mut warnings = Vec::new();
+        unknown_fields("params", params, &["user", "particle", "env", "app"], &mut warnings);
+        unknown_fields("params.user", &params["user"], &["address", "nickname"], &mut warnings);
+        unknown_fields("params.env", &params["env"], &["app_version", "locale", "network"], &mut warnings);
+
+        Ok((context, warnings))
+    }
+
+    /// Install the `cyb::context::*` constants into the `cyb` module.
+    pub(crate) fn install(&self, module: &mut Module) -> Result<(), ContextError> {
+        module.constant(["context"], constant(&self.app))?;
+        module.constant(["context", "app"], constant(&self.app))?;
+        module.constant(["context", "user"], constant(&self.user))?;
+        module.constant(["context", "particle"], constant(&self.particle))?;
+        module.constant(["context", "env"], constant(&self.env))?;
+        Ok(())
+    }
+}
+
+/// Warn about the fields of `value` not in `known`.
+fn unknown_fields(path: &str, value: &SerdeValue, known: &[&str], warnings: &mut Vec<String>) {
+    if let SerdeValue::Object(object) = value {
+        for field in object.keys().filter(|field| !known.contains(&field.as_str())) {
+            warnings.push(format!("unknown run param `{}.{}` is ignored", path, field));
+        }
+    }
+}
+
+fn constant<T>(value: &T) -> VmValue
+where
+    T: Serialize,
+{
+    map_to_rune_value(&serde_json::to_value(value).unwrap_or_default())
+}
@@ -1,99 +1,425 @@
-use rune::{ContextError, Module};
-use js_sys::Promise;
-use rune::runtime::{Object, VmResult, Vec, Value as VmValue};
-use wasm_bindgen::prelude::*;
-use serde_json::Value as SerdeValue;
-use gloo_utils::format::JsValueSerdeExt;
+use rune::{vm_try, ContextError, Module};
+use rune::runtime::{FromValue, Object, VmResult, Vec, Value as VmValue};
+use serde::Deserialize;
+use serde_json::{json, Value as SerdeValue};
 
-use crate::helpers::{map_to_rune_value, execute_promise};
-
-#[wasm_bindgen(raw_module = "../../src/services/scripting/wasmBindings.js")]
-extern "C" {
-    fn jsCyberSearch(query: &str)-> Promise;
-    fn jsCyberLink(fromCid: &str, toCid: &str)-> Promise;
-    fn jsGetPassportByNickname(nickname: &str)-> Promise;
-    fn jsGetIpfsTextContent(cid: &str)-> Promise;
-    fn jsAddContenToIpfs(content: &str)-> Promise;
-    fn jsEvalScriptFromIpfs(cid: &str, func_name: &str, params: &JsValue)-> Promise;
-    fn jsPromptToOpenAI(prompt: &str, api_key: &str)-> Promise;
-    // fn js_cyberLinksFrom(cid: &str)-> Promise;
-    // fn js_cyberLinksTo(cid: &str)-> Promise;
+use crate::cid;
+use crate::context::RunContext;
+use crate::eval;
+use crate::helpers::{from_json, from_options};
+use crate::host::{CybHost, HostFuture, Page, SearchOptions, SearchOrder};
+use crate::session;
+use crate::types::{
+    self, Coin, Cyberlink, IpfsContent, IpfsStat, LinksPage, Neuron, NeuronEnergy, ParticleStats, Passport,
+    SearchPage, SearchResult, Secret, StackFrame,
+};
 
+pub fn log(message: &str) -> VmResult<()> {
+    let session = vm_try!(session::current());
+    session.host().log(&session.redact(message));
+    VmResult::Ok(())
+}
 
+pub fn now() -> VmResult<i64> {
+    vm_try!(session::current()).now()
+}
 
-    // fn js_execUserScript(nickname: &str, scriptName: &str)-> Promise;
+pub async fn cyber_search(query: &str) ->  VmResult<VmValue> {
+    let session = vm_try!(session::current());
+    session.call("cyber_search", json!([query]), |host| host.cyber_search(query)).await
+}
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SearchResponse {
+    Page {
+        results: std::vec::Vec<SearchResult>,
+        #[serde(default)]
+        total: Option<i64>,
+    },
+    All(std::vec::Vec<SearchResult>),
+    Empty(()),
 }
 
-#[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    pub fn log(s: &str);
+/// Largest number of entries of a page.
+const MAX_PAGE_LIMIT: u64 = 1000;
+
+/// The offset following a page of `count` entries at `offset`, failing if
+/// it doesn't fit the offsets handed to scripts.
+fn page_end(name: &str, offset: u64, count: u64) -> VmResult<u64> {
+    match offset.checked_add(count).filter(|&end| i64::try_from(end).is_ok()) {
+        Some(end) => VmResult::Ok(end),
+        None => VmResult::panic(format!("{}: offset {} is out of range", name, offset)),
+    }
 }
 
-pub async fn cyber_search(query: &str) ->  VmResult<VmValue> {
-    execute_promise(|| jsCyberSearch(query)).await
+/// Search with `options` being
+/// `#{ offset, limit, page, order: "rank" | "time", neurons: [..] }`.
+pub async fn search(query: &str, options: Object) -> VmResult<SearchPage> {
+    let mut options: SearchOptions = vm_try!(from_options("search", &options));
+
+    options.limit = options.limit.min(MAX_PAGE_LIMIT);
+
+    if let Some(page) = options.page.take() {
+        options.offset = match page.checked_mul(options.limit) {
+            Some(offset) => offset,
+            None => return VmResult::panic(format!("search: page {} is out of range", page)),
+        };
+    }
+
+    let session = vm_try!(session::current());
+    let args = json!([query, options]);
+    let result = vm_try!(session.call_json("search", args, |host| host.cyber_search_page(query, &options)).await);
+
+    let (mut results, total) = match vm_try!(from_json("search", result)) {
+        SearchResponse::Page { results, total } => (results, total),
+        SearchResponse::All(mut results) => {
+            if !options.neurons.is_empty() {
+                results.retain(|r| r.neuron.as_ref().map_or(false, |n| options.neurons.contains(n)));
+            }
+
+            match options.order {
+                SearchOrder::Rank => results.sort_by(|a, b| b.rank.total_cmp(&a.rank)),
+                SearchOrder::Time => results.sort_by(|a, b| b.timestamp.cmp(&a.timestamp)),
+            }
+
+            let total = results.len() as i64;
+            let skip = usize::try_from(options.offset).unwrap_or(usize::MAX);
+            let results = results.into_iter().skip(skip).collect();
+            (results, Some(total))
+        }
+        SearchResponse::Empty(()) => (std::vec::Vec::new(), Some(0)),
+    };
+
+    results.truncate(options.limit as usize);
+
+    let end = vm_try!(page_end("search", options.offset, results.len() as u64));
+
+    let more = match total {
+        Some(total) => end < total as u64,
+        None => !results.is_empty() && results.len() as u64 == options.limit,
+    };
+
+    VmResult::Ok(SearchPage {
+        results,
+        offset: options.offset as i64,
+        total,
+        next: more.then(|| end as i64),
+    })
 }
 
 pub async fn cyber_link(from_cid: &str, to_cid: &str) ->  VmResult<VmValue> {
-    execute_promise(|| jsCyberLink(from_cid, to_cid)).await
+    let session = vm_try!(session::current());
+    session.call("cyber_link", json!([from_cid, to_cid]), |host| host.cyber_link(from_cid, to_cid)).await
 }
 
 pub async fn get_passport_by_nickname(nickname: &str) ->  VmResult<VmValue> {
-    execute_promise(|| jsGetPassportByNickname(nickname)).await
+    let session = vm_try!(session::current());
+    session.call("get_passport_by_nickname", json!([nickname]), |host| host.get_passport_by_nickname(nickname)).await
 }
 
 pub async fn get_text_from_ipfs(cid: &str) ->  VmResult<VmValue> {
-    execute_promise(|| jsGetIpfsTextContent(cid)).await
+    let session = vm_try!(session::current());
+    session.call("get_text_from_ipfs", json!([cid]), |host| host.get_text_from_ipfs(cid)).await
 }
 
-pub async fn eval_script_from_ipfs(cid: &str, func_name: &str, params: Vec) ->  VmResult<VmValue> {
-    // let params: Object = rune::from_value(params.unwrap_or(Vec::new())).unwrap();
-    let json_value: SerdeValue = serde_json::to_value(params.into_inner()).unwrap();
+/// Content of a particle with its type and size, `None` if not found.
+pub async fn ipfs_get(cid: &str) -> VmResult<Option<IpfsContent>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("ipfs_get", json!([cid]), |host| host.ipfs_get(cid)).await);
 
-    // let json_value: SerdeValue = serde_json::to_value(params.unwrap_or(Vec::new()).into_inner()).unwrap();
+    match result {
+        SerdeValue::Null => VmResult::Ok(None),
+        result => {
+            let raw = vm_try!(from_json("ipfs_get", result));
+            VmResult::Ok(Some(IpfsContent::new(cid.to_owned(), raw)))
+        }
+    }
+}
 
-    let js_value =  <JsValue as JsValueSerdeExt>::from_serde(&json_value).unwrap();
+/// Metadata of a particle, `None` if not found.
+pub async fn ipfs_stat(cid: &str) -> VmResult<Option<IpfsStat>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("ipfs_stat", json!([cid]), |host| host.ipfs_stat(cid)).await);
 
-    execute_promise(|| jsEvalScriptFromIpfs(cid, func_name, &js_value)).await
+    match result {
+        SerdeValue::Null => VmResult::Ok(None),
+        result => {
+            let mut stat: IpfsStat = vm_try!(from_json("ipfs_stat", result));
+            stat.cid = cid.to_owned();
+            VmResult::Ok(Some(stat))
+        }
+    }
+}
+
+/// Run `func_name` of the script stored at `cid` with `params`, sharing this
+/// script's budget and permissions.
+pub async fn eval_script_from_ipfs(cid: &str, func_name: &str, params: Vec) -> VmResult<VmValue> {
+    eval::eval(cid, func_name, params.into_inner(), false).await
+}
+
+/// The chain of scripts that led to the current one, outermost first.
+pub fn call_stack() -> VmResult<std::vec::Vec<StackFrame>> {
+    let frames = vm_try!(session::current()).call_stack();
+
+    VmResult::Ok(
+        frames
+            .into_iter()
+            .filter(|frame| !frame.tool)
+            .map(|frame| StackFrame {
+                cid: frame.cid,
+                func_name: frame.func_name,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct EvalOptions {
+    read_only: bool,
+}
+
+/// Like `eval_script_from_ipfs` with `options` being `#{ read_only }`. A
+/// read-only caller can't make the script writable.
+pub async fn eval_script_from_ipfs_with(cid: &str, func_name: &str, params: Vec, options: Object) -> VmResult<VmValue> {
+    let options: EvalOptions = vm_try!(from_options("eval_script_from_ipfs_with", &options));
+    eval::eval(cid, func_name, params.into_inner(), options.read_only).await
 }
 
 pub async fn add_content_to_ipfs(content: &str) ->  VmResult<VmValue> {
-    execute_promise(|| jsAddContenToIpfs(content)).await
+    let session = vm_try!(session::current());
+    session.call("add_content_to_ipfs", json!([content]), |host| host.add_content_to_ipfs(content)).await
+}
+
+/// Handle to the secret `name` held by the host, to pass to host functions
+/// in place of e.g. an API key.
+pub fn secret(name: &str) -> Secret {
+    Secret { name: name.to_owned() }
+}
+
+/// Plain API keys shorter than this are not redacted, since replacing them
+/// would mangle unrelated text.
+const MIN_PLAIN_KEY: usize = 8;
+
+/// An API key, given as is or as a [Secret].
+pub(crate) enum ApiKey {
+    Plain(String),
+    Secret(Secret),
+}
+
+impl FromValue for ApiKey {
+    fn from_value(value: VmValue) -> VmResult<Self> {
+        match value {
+            VmValue::Any(..) => VmResult::Ok(ApiKey::Secret(vm_try!(Secret::from_value(value)))),
+            value => VmResult::Ok(ApiKey::Plain(vm_try!(String::from_value(value)))),
+        }
+    }
+}
+
+impl ApiKey {
+    /// The value of the key, only resolved if the host is actually called.
+    async fn resolve(self, session: &session::Session) -> VmResult<String> {
+        match self {
+            ApiKey::Plain(_) if session.host().streams_output() => VmResult::panic(
+                "plain API keys can't be used while output is streamed, pass `cyb::secret(name)` instead",
+            ),
+            ApiKey::Plain(key) => {
+                if key.len() >= MIN_PLAIN_KEY {
+                    session.add_secret(&key);
+                }
+
+                VmResult::Ok(key)
+            }
+            ApiKey::Secret(secret) if session.is_live() => session.secret(&secret).await,
+            ApiKey::Secret(_) => VmResult::Ok(String::new()),
+        }
+    }
 }
 
-pub async fn open_ai_prompt(prompt: &str, api_key: &str) ->  VmResult<VmValue> {
-    execute_promise(|| jsPromptToOpenAI(prompt, api_key)).await
+pub async fn open_ai_prompt(prompt: &str, api_key: ApiKey) ->  VmResult<VmValue> {
+    // NB: the api key is left out of the call arguments so it never ends up
+    // in a snapshot.
+    let session = vm_try!(session::current());
+    let api_key = vm_try!(api_key.resolve(&session).await);
+    session.call("open_ai_prompt", json!([prompt]), |host| host.open_ai_prompt(prompt, &api_key)).await
 }
 
-// pub async fn get_cyberlinks_from_cid(cid: &str) ->  VmResult<VmValue> {
-//     execute_promise(|| js_cyberLinksFrom(cid)).await
-// }
+#[derive(Deserialize)]
+struct LinksResponse {
+    #[serde(default)]
+    links: std::vec::Vec<Cyberlink>,
+    #[serde(default)]
+    total: Option<i64>,
+}
+
+/// Query a page of links through the host call `name`.
+pub(crate) async fn links<F>(name: &str, cid: &str, mut page: Page, live: F) -> VmResult<LinksPage>
+where
+    F: FnOnce(&dyn CybHost, &Page) -> HostFuture,
+{
+    page.limit = page.limit.min(MAX_PAGE_LIMIT);
+
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json(name, json!([cid, page]), |host| live(host, &page)).await);
+    let mut response: LinksResponse = vm_try!(from_json(name, result));
+
+    response.links.truncate(page.limit as usize);
+
+    let count = response.links.len() as u64;
+    let end = vm_try!(page_end(name, page.offset, count));
+
+    let more = match response.total {
+        Some(total) => end < total as u64,
+        None => count > 0 && count == page.limit,
+    };
+
+    VmResult::Ok(LinksPage {
+        links: response.links,
+        offset: page.offset as i64,
+        total: response.total,
+        next: more.then(|| end as i64),
+    })
+}
+
+/// `options` is `#{ offset, limit }`.
+pub async fn cyber_links_from(cid: &str, options: Object) -> VmResult<LinksPage> {
+    let page = vm_try!(from_options("cyber_links_from", &options));
+    links("cyber_links_from", cid, page, |host, page| host.cyber_links_from(cid, page)).await
+}
+
+/// `options` is `#{ offset, limit }`.
+pub async fn cyber_links_to(cid: &str, options: Object) -> VmResult<LinksPage> {
+    let page = vm_try!(from_options("cyber_links_to", &options));
+    links("cyber_links_to", cid, page, |host, page| host.cyber_links_to(cid, page)).await
+}
+
+
+pub async fn particle_rank(cid: &str) -> VmResult<f64> {
+    #[derive(Deserialize)]
+    struct Rank(#[serde(deserialize_with = "types::number")] f64);
 
-// pub async fn get_cyberlinks_to_cid(cid: &str) ->  VmResult<VmValue> {
-//     execute_promise(|| js_cyberLinksTo(cid)).await
-// }
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("particle_rank", json!([cid]), |host| host.particle_rank(cid)).await);
+    let Rank(rank) = vm_try!(from_json("particle_rank", result));
+    VmResult::Ok(rank)
+}
 
+pub async fn particle_stats(cid: &str) -> VmResult<ParticleStats> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("particle_stats", json!([cid]), |host| host.particle_stats(cid)).await);
+    let mut stats: ParticleStats = vm_try!(from_json("particle_stats", result));
 
+    if stats.cid.is_empty() {
+        stats.cid = cid.to_owned();
+    }
+
+    VmResult::Ok(stats)
+}
+
+pub async fn neuron_balance(address: &str) -> VmResult<std::vec::Vec<Coin>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("neuron_balance", json!([address]), |host| host.neuron_balance(address)).await);
+    from_json("neuron_balance", result)
+}
+
+pub async fn neuron_energy(address: &str) -> VmResult<NeuronEnergy> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("neuron_energy", json!([address]), |host| host.neuron_energy(address)).await);
+    let mut energy: NeuronEnergy = vm_try!(from_json("neuron_energy", result));
+
+    if energy.address.is_empty() {
+        energy.address = address.to_owned();
+    }
+
+    VmResult::Ok(energy)
+}
+
+/// Decode a passport, `null` and `{}` meaning there is none.
+fn passport(name: &str, result: SerdeValue) -> VmResult<Option<Passport>> {
+    match result {
+        SerdeValue::Null => VmResult::Ok(None),
+        SerdeValue::Object(object) if object.is_empty() => VmResult::Ok(None),
+        result => VmResult::Ok(Some(vm_try!(from_json(name, result)))),
+    }
+}
+
+pub async fn passport_by_nickname(nickname: &str) -> VmResult<Option<Passport>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("get_passport_by_nickname", json!([nickname]), |host| host.get_passport_by_nickname(nickname)).await);
+    passport("passport_by_nickname", result)
+}
+
+pub async fn passport_by_address(address: &str) -> VmResult<Option<Passport>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("passport_by_address", json!([address]), |host| host.passport_by_address(address)).await);
+    passport("passport_by_address", result)
+}
+
+pub async fn passport_by_token_id(token_id: &str) -> VmResult<Option<Passport>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("passport_by_token_id", json!([token_id]), |host| host.passport_by_token_id(token_id)).await);
+    passport("passport_by_token_id", result)
+}
+
+pub async fn passports_by_particle(cid: &str) -> VmResult<std::vec::Vec<Passport>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("passports_by_particle", json!([cid]), |host| host.passports_by_particle(cid)).await);
+
+    match result {
+        SerdeValue::Null => VmResult::Ok(std::vec::Vec::new()),
+        result => from_json("passports_by_particle", result),
+    }
+}
+
+/// The neuron running the script, `None` if the host doesn't know it.
+pub async fn me() -> VmResult<Option<Neuron>> {
+    let session = vm_try!(session::current());
+    let result = vm_try!(session.call_json("me", json!([]), |host| host.me()).await);
+
+    match result {
+        SerdeValue::Null => VmResult::Ok(None),
+        result => VmResult::Ok(Some(vm_try!(from_json("me", result)))),
+    }
+}
 
 /// The wasm 'cyb' module.
-pub fn module(params: SerdeValue, read_only: bool) -> Result<Module, ContextError> {
+pub(crate) fn module(context: &RunContext, read_only: bool) -> Result<Module, ContextError> {
     let mut module = Module::with_crate("cyb");
 
-    module.constant(["context"], map_to_rune_value(&params["app"]))?;
+    context.install(&mut module)?;
 
     module.function(["log"], log)?;
+    module.function(["now"], now)?;
     module.function(["cyber_search"], cyber_search)?;
+    module.function(["search"], search)?;
 
     module.function(["get_passport_by_nickname"], get_passport_by_nickname)?;
+    module.function(["passport_by_nickname"], passport_by_nickname)?;
+    module.function(["passport_by_address"], passport_by_address)?;
+    module.function(["passport_by_token_id"], passport_by_token_id)?;
+    module.function(["passports_by_particle"], passports_by_particle)?;
 
     module.function(["get_text_from_ipfs"], get_text_from_ipfs)?;
+    module.function(["ipfs_get"], ipfs_get)?;
+    module.function(["ipfs_stat"], ipfs_stat)?;
 
     module.function(["eval_script_from_ipfs"], eval_script_from_ipfs)?;
+    module.function(["eval_script_from_ipfs_with"], eval_script_from_ipfs_with)?;
+    module.function(["call_stack"], call_stack)?;
 
+    module.function(["secret"], secret)?;
     module.function(["open_ai_prompt"], open_ai_prompt)?;
-    // module.function(["get_cyberlinks_from_cid"], get_cyberlinks_from_cid)?;
-    // module.function(["get_cyberlinks_to_cid"], get_cyberlinks_to_cid)?;
+
+    types::install(&mut module)?;
+    cid::install(&mut module)?;
+    module.function(["cyber_links_from"], cyber_links_from)?;
+    module.function(["cyber_links_to"], cyber_links_to)?;
+    module.function(["particle_rank"], particle_rank)?;
+    module.function(["particle_stats"], particle_stats)?;
+    module.function(["neuron_balance"], neuron_balance)?;
+    module.function(["neuron_energy"], neuron_energy)?;
+    module.function(["me"], me)?;
 
     // non read-only functions
     // if not readOnly param, then trait as read-only
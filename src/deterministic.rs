@@ -0,0 +1,184 @@
+//! Deterministic mode: pinned clock, snapshot host calls and a seeded `rand`.
+
+use std::cell::Cell;
+
+use rune::runtime::VmResult;
+use rune::{vm_try, Any, ContextError, Module};
+use serde::Deserialize;
+use serde_json::Value as SerdeValue;
+
+use crate::session::{self, HostCall};
+
+/// Deterministic execution settings.
+///
+/// Randomness is seeded, wall-clock access is pinned and host calls are only
+/// answered from the snapshot, so the same script with the same params always
+/// produces the same result.
+#[derive(Deserialize)]
+pub(crate) struct Deterministic {
+    /// Seed for the `rand` module.
+    #[serde(default)]
+    pub(crate) seed: u64,
+    /// Value returned by `cyb::now`, in milliseconds since the epoch. Reading
+    /// the clock fails if unset.
+    #[serde(default)]
+    pub(crate) now: Option<i64>,
+    /// Host call results served instead of calling the host.
+    #[serde(default)]
+    pub(crate) snapshot: Vec<HostCall>,
+}
+
+impl Deterministic {
+    /// Look up the result of a host call in the snapshot.
+    pub(crate) fn lookup(&self, name: &str, args: &SerdeValue) -> VmResult<SerdeValue> {
+        let recorded = self
+            .snapshot
+            .iter()
+            .find(|recorded| recorded.call == name && recorded.args == *args);
+
+        match recorded {
+            Some(recorded) => VmResult::Ok(recorded.result.clone()),
+            None => VmResult::panic(format!(
+                "non-deterministic host call `{}({})` is not in the snapshot",
+                name, args
+            )),
+        }
+    }
+}
+
+/// The WyRand generator, the same algorithm `rune_modules::rand` uses.
+///
+/// Also the `rand::WyRand` type of the seeded `rand` module.
+#[derive(Any)]
+pub(crate) struct WyRand {
+    state: Cell<u64>,
+}
+
+impl WyRand {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self {
+            state: Cell::new(seed),
+        }
+    }
+
+    pub(crate) fn next(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0xa076_1d64_78bd_642f);
+        self.state.set(state);
+        let t = u128::from(state).wrapping_mul(u128::from(state ^ 0xe703_7ed1_a0b4_28db));
+        ((t >> 64) ^ t) as u64
+    }
+}
+
+/// The PCG XSL RR 128/64 generator, the `rand::Pcg64` type of the seeded
+/// `rand` module.
+#[derive(Any)]
+pub(crate) struct Pcg64 {
+    state: Cell<u128>,
+}
+
+impl Pcg64 {
+    const MULTIPLIER: u128 = 0x2360_ed05_1fc6_5da4_4385_df64_9fcc_f645;
+    const INCREMENT: u128 = 0x0a02_bdbf_7bb3_c0a7_ac28_fa16_a64a_bf96 | 1;
+
+    fn new(seed: u128) -> Self {
+        let rng = Self {
+            state: Cell::new(seed.wrapping_add(Self::INCREMENT)),
+        };
+
+        rng.step();
+        rng
+    }
+
+    fn step(&self) {
+        let state = self.state.get();
+        self.state.set(state.wrapping_mul(Self::MULTIPLIER).wrapping_add(Self::INCREMENT));
+    }
+
+    fn next(&self) -> u64 {
+        self.step();
+        let state = self.state.get();
+        let rotate = (state >> 122) as u32;
+        (((state >> 64) as u64) ^ (state as u64)).rotate_right(rotate)
+    }
+}
+
+/// Draw a number in `lower..upper` from `value`.
+fn range(name: &str, value: u64, lower: i64, upper: i64) -> VmResult<i64> {
+    if upper <= lower {
+        return VmResult::panic(format!("{}: upper bound must be greater than lower bound", name));
+    }
+
+    let span = upper.wrapping_sub(lower) as u64;
+    VmResult::Ok(lower.wrapping_add((value % span) as i64))
+}
+
+fn int() -> VmResult<i64> {
+    let session = vm_try!(session::current());
+    VmResult::Ok(session.next_random() as i64)
+}
+
+fn int_range(lower: i64, upper: i64) -> VmResult<i64> {
+    let session = vm_try!(session::current());
+    range("rand::int_range", session.next_random(), lower, upper)
+}
+
+/// A `WyRand` seeded from the run's seed.
+fn wyrand_new() -> VmResult<WyRand> {
+    let session = vm_try!(session::current());
+    VmResult::Ok(WyRand::new(session.next_random()))
+}
+
+fn wyrand_new_seed(seed: i64) -> WyRand {
+    WyRand::new(seed as u64)
+}
+
+fn wyrand_int(rng: &WyRand) -> i64 {
+    rng.next() as i64
+}
+
+fn wyrand_int_range(rng: &WyRand, lower: i64, upper: i64) -> VmResult<i64> {
+    range("WyRand::int_range", rng.next(), lower, upper)
+}
+
+/// A `Pcg64` seeded from the run's seed.
+fn pcg64_new() -> VmResult<Pcg64> {
+    let session = vm_try!(session::current());
+    let seed = (u128::from(session.next_random()) << 64) | u128::from(session.next_random());
+    VmResult::Ok(Pcg64::new(seed))
+}
+
+fn pcg64_new_seed(seed: i64) -> Pcg64 {
+    Pcg64::new(u128::from(seed as u64))
+}
+
+fn pcg64_int(rng: &Pcg64) -> i64 {
+    rng.next() as i64
+}
+
+fn pcg64_int_range(rng: &Pcg64, lower: i64, upper: i64) -> VmResult<i64> {
+    range("Pcg64::int_range", rng.next(), lower, upper)
+}
+
+/// A seeded replacement for the `rand` module used in sealed runs, with the
+/// same functions and types as `rune_modules::rand`. Generators created with
+/// `new()` are seeded from the run's seed.
+pub(crate) fn rand_module() -> Result<Module, ContextError> {
+    let mut module = Module::with_crate("rand");
+
+    module.function(["int"], int)?;
+    module.function(["int_range"], int_range)?;
+
+    module.ty::<WyRand>()?;
+    module.function(["WyRand", "new"], wyrand_new)?;
+    module.function(["WyRand", "new_seed"], wyrand_new_seed)?;
+    module.associated_function("int", wyrand_int)?;
+    module.associated_function("int_range", wyrand_int_range)?;
+
+    module.ty::<Pcg64>()?;
+    module.function(["Pcg64", "new"], pcg64_new)?;
+    module.function(["Pcg64", "new_seed"], pcg64_new_seed)?;
+    module.associated_function("int", pcg64_int)?;
+    module.associated_function("int_range", pcg64_int_range)?;
+
+    Ok(module)
+}
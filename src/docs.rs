@@ -0,0 +1,32 @@
+//! Doc comments of the functions of a unit.
+//!
+//! Units don't keep doc comments, so they are collected while compiling and
+//! stored next to the unit, in the cache and in precompiled units. They
+//! describe script functions exposed to LLMs as tools.
+
+use std::collections::BTreeMap;
+
+use rune::compile::{CompileVisitor, Item, Location};
+use rune::Hash;
+
+/// Doc comments keyed by item, e.g. `search_notes` or `notes::add`.
+pub(crate) type Docs = BTreeMap<String, String>;
+
+/// Compile visitor collecting the doc comments of every item.
+#[derive(Default)]
+pub(crate) struct DocVisitor {
+    pub(crate) docs: Docs,
+}
+
+impl CompileVisitor for DocVisitor {
+    fn visit_doc_comment(&mut self, _: Location, item: &Item, _: Hash, docstr: &str) {
+        let doc = self.docs.entry(item.to_string()).or_default();
+
+        if !doc.is_empty() {
+            doc.push('\n');
+        }
+
+        // NB: `/// text` comes in as ` text`.
+        doc.push_str(docstr.strip_prefix(' ').unwrap_or(docstr));
+    }
+}
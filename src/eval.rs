@@ -0,0 +1,204 @@
+//! Nested evaluation of scripts stored in IPFS.
+//!
+//! Scripts called through `cyb::eval_script_from_ipfs` are compiled and run
+//! by the crate itself, inside the caller's session. They draw from the
+//! caller's instruction budget, can only be given fewer permissions than the
+//! caller has, and exchange Rune values with it directly. A script can't
+//! appear twice on the call stack, which is also limited in depth.
+
+use std::sync::Arc;
+
+use rune::runtime::{VmResult, Value as VmValue};
+use rune::termcolor::Buffer;
+use rune::{vm_try, Diagnostics, Options, Source, Sources, Vm};
+use serde_json::{json, Value as SerdeValue};
+
+use crate::cache::{self, CachedUnit};
+use crate::context::RunContext;
+use crate::profile;
+use crate::docs::DocVisitor;
+use crate::session::{self, Frame, Program, Session};
+
+/// Default largest nesting depth of evaluated scripts.
+pub(crate) const MAX_DEPTH: usize = 8;
+
+/// How the top-level run was set up, used to set up nested scripts the same
+/// way.
+pub(crate) struct Environment {
+    /// Parameters the `cyb` module was built with.
+    pub(crate) params: RunContext,
+    /// Whether the `std::experiments` package is installed.
+    pub(crate) experimental: bool,
+    /// Whether the run is sealed, see [crate::setup_context].
+    pub(crate) sealed: bool,
+    /// Compiler options.
+    pub(crate) options: Vec<String>,
+    /// Largest nesting depth of evaluated scripts.
+    pub(crate) max_depth: usize,
+}
+
+/// Run `func_name` of the script stored at `cid` with `args`.
+///
+/// The script is read-only if the caller is or if `read_only` is set. It
+/// fails if the script is already being evaluated further up the call stack.
+pub(crate) async fn eval(cid: &str, func_name: &str, args: Vec<VmValue>, read_only: bool) -> VmResult<VmValue> {
+    let session = vm_try!(session::current());
+    let read_only = session.read_only() || read_only;
+
+    let frames = vm_try!(session.enter(Frame {
+        cid: Some(cid.to_owned()),
+        scope: Some(cid.to_owned()),
+        func_name: func_name.to_owned(),
+        read_only,
+        tool: false,
+        program: None,
+    }));
+
+    let future = run(&session, cid, func_name, args, read_only);
+    session::with_frames(session.clone(), frames, future).await
+}
+
+async fn run(session: &Session, cid: &str, func_name: &str, args: Vec<VmValue>, read_only: bool) -> VmResult<VmValue> {
+    let source = vm_try!(session.call_json("get_text_from_ipfs", json!([cid]), |host| host.get_text_from_ipfs(cid)).await);
+
+    let source = match source {
+        SerdeValue::String(source) => source,
+        _ => return fallback(session, cid, func_name, args, read_only).await,
+    };
+
+    let env = session.environment();
+
+    let profile = match profile::get(env.experimental, read_only, env.sealed) {
+        Ok(profile) => profile,
+        Err(error) => return VmResult::panic(format!("failed to set up `{}`: {}", cid, error)),
+    };
+
+    session.use_profile(&profile);
+
+    let mut options = Options::default();
+
+    for option in &env.options {
+        if let Err(error) = options.parse_option(option) {
+            return VmResult::panic(format!("failed to set up `{}`: {}", cid, error));
+        }
+    }
+
+    let mut sources = Sources::new();
+    sources.insert(Source::new(cid, source.as_str()));
+
+    let key = cache::key(
+        &[source.as_str()],
+        &env.options,
+        env.experimental,
+        read_only,
+        env.sealed,
+        &env.params,
+    );
+
+    let (unit, docs) = match cache::get(&key) {
+        Some(cached) => (cached.unit, cached.docs),
+        None => {
+            let context = match profile.context(&env.params) {
+                Ok(context) => context,
+                Err(error) => return VmResult::panic(format!("failed to set up `{}`: {}", cid, error)),
+            };
+
+            let mut diagnostics = Diagnostics::new();
+            let mut visitor = DocVisitor::default();
+
+            let unit = rune::prepare(&mut sources)
+                .with_context(&context)
+                .with_diagnostics(&mut diagnostics)
+                .with_options(&options)
+                .with_visitor(&mut visitor)
+                .build();
+
+            let unit = match unit {
+                Ok(unit) => Arc::new(unit),
+                Err(_) => {
+                    let mut writer = Buffer::no_color();
+                    let _ = diagnostics.emit(&mut writer, &sources);
+                    let output = String::from_utf8_lossy(writer.as_slice()).trim_end().to_owned();
+                    return VmResult::panic(format!("failed to compile `{}`:\n{}", cid, output));
+                }
+            };
+
+            let docs = Arc::new(visitor.docs);
+
+            cache::insert(key, CachedUnit {
+                unit: unit.clone(),
+                docs: docs.clone(),
+                diagnostics: Vec::new(),
+                rendered: Vec::new(),
+            });
+
+            (unit, docs)
+        }
+    };
+
+    session.set_program(Program {
+        unit: unit.clone(),
+        runtime: profile.runtime(),
+        docs,
+    });
+
+    let mut vm = Vm::new(profile.runtime(), unit);
+    let mut execution = vm_try!(vm.execute([func_name], args));
+
+    let result = execution.async_complete().await;
+
+    // NB: errors carry locations in the nested unit, which the caller has no
+    // sources for, so they are turned into panics naming the position.
+    match result {
+        VmResult::Ok(value) => VmResult::Ok(value),
+        VmResult::Err(error) => {
+            let vm = execution.vm();
+
+            let (unit, ip) = match error.first_location() {
+                Some(loc) => (&loc.unit, loc.ip),
+                None => (vm.unit(), vm.ip()),
+            };
+
+            let position = unit
+                .debug_info()
+                .and_then(|debug| debug.instruction_at(ip))
+                .and_then(|inst| {
+                    let source = sources.get(inst.source_id)?;
+                    Some(source.pos_to_utf8_linecol(inst.span.start.into_usize()))
+                });
+
+            match position {
+                Some((line, col)) => VmResult::panic(format!(
+                    "`{}` in `{}` failed at {}:{}:{}: {}",
+                    func_name,
+                    cid,
+                    cid,
+                    line + 1,
+                    col + 1,
+                    error
+                )),
+                None => VmResult::panic(format!("`{}` in `{}` failed: {}", func_name, cid, error)),
+            }
+        }
+    }
+}
+
+/// Run `func_name` of a script whose source wasn't found with the deprecated
+/// [crate::host::CybHost::eval_script_from_ipfs]. The host runs it outside of
+/// this VM and can't narrow its permissions, so read-only callers can't.
+async fn fallback(session: &Session, cid: &str, func_name: &str, args: Vec<VmValue>, read_only: bool) -> VmResult<VmValue> {
+    if read_only {
+        return VmResult::panic(format!("script `{}` not found", cid));
+    }
+
+    let params = match args.iter().map(serde_json::to_value).collect::<Result<Vec<_>, _>>() {
+        Ok(params) => SerdeValue::Array(params),
+        Err(error) => return VmResult::panic(format!("eval_script_from_ipfs: {}", error)),
+    };
+
+    let recorded = json!([cid, func_name, params]);
+
+    session
+        .call("eval_script_from_ipfs", recorded, |host| host.eval_script_from_ipfs(cid, func_name, &params))
+        .await
+}
@@ -0,0 +1,210 @@
+//! Host functions registered from JavaScript at runtime.
+//!
+//! Apps embedding the crate can add their own async functions (wallet, UI,
+//! analytics, ...) into a module of their choosing before compiling scripts.
+//! Arguments and return values are converted the same way as for the `cyb`
+//! bindings, and calls go through the session so they are recorded, replayed
+//! and gated by deterministic mode like any other host call.
+
+use std::cell::RefCell;
+
+use anyhow::{anyhow, bail};
+use gloo_utils::format::JsValueSerdeExt;
+use js_sys::{Array, Function, Promise};
+use rune::runtime::{VmResult, Value as VmValue};
+use rune::{vm_try, ContextError, Module};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as SerdeValue;
+use wasm_bindgen::JsValue;
+
+use crate::helpers::resolve_promise;
+use crate::host::{ready, HostFuture};
+use crate::session;
+
+/// Largest number of arguments a registered function can take.
+const MAX_ARITY: usize = 4;
+
+/// Modules provided by the crate, which can't be extended.
+const RESERVED_MODULES: &[&str] = &["cyb", "std", "http", "json", "toml", "rand", "experiments"];
+
+thread_local! {
+    static REGISTRY: RefCell<Vec<Extension>> = RefCell::new(Vec::new());
+}
+
+/// What a registered function is allowed to do.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Capability {
+    /// Only reads data, always available.
+    Read,
+    /// Modifies data, left out of read-only runs.
+    Write,
+}
+
+/// Description of a function registered from JavaScript.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Descriptor {
+    /// The Rune module the function is installed into.
+    pub(crate) module: String,
+    /// Name of the function.
+    pub(crate) name: String,
+    /// Number of arguments the function takes.
+    pub(crate) arity: usize,
+    /// Capability tag of the function.
+    pub(crate) capability: Capability,
+    /// Documentation shown to script authors.
+    #[serde(default)]
+    pub(crate) doc: Option<String>,
+}
+
+struct Extension {
+    descriptor: Descriptor,
+    handler: Function,
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Register `handler` under the given descriptor, replacing any function
+/// previously registered under the same name.
+pub(crate) fn register(descriptor: Descriptor, handler: Function) -> anyhow::Result<()> {
+    if !is_identifier(&descriptor.module) || !is_identifier(&descriptor.name) {
+        bail!(
+            "`{}::{}` is not a valid function path",
+            descriptor.module,
+            descriptor.name
+        );
+    }
+
+    if RESERVED_MODULES.contains(&descriptor.module.as_str()) {
+        bail!("functions can't be registered into the `{}` module", descriptor.module);
+    }
+
+    if descriptor.arity > MAX_ARITY {
+        bail!(
+            "`{}::{}` takes {} arguments, at most {} are supported",
+            descriptor.module,
+            descriptor.name,
+            descriptor.arity,
+            MAX_ARITY
+        );
+    }
+
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+
+        registry.retain(|e| {
+            e.descriptor.module != descriptor.module || e.descriptor.name != descriptor.name
+        });
+
+        registry.push(Extension {
+            descriptor,
+            handler,
+        });
+    });
+
+    Ok(())
+}
+
+/// Descriptors of every registered function.
+pub(crate) fn descriptors() -> Vec<Descriptor> {
+    REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|e| e.descriptor.clone())
+            .collect()
+    })
+}
+
+/// Call the JS handler of a registered function.
+fn invoke(module: &str, name: &str, args: &[SerdeValue]) -> HostFuture {
+    let handler = REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .find(|e| e.descriptor.module == module && e.descriptor.name == name)
+            .map(|e| e.handler.clone())
+    });
+
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return ready(Err(anyhow!("`{}::{}` is no longer registered", module, name))),
+    };
+
+    let args = args
+        .iter()
+        .map(|arg| <JsValue as JsValueSerdeExt>::from_serde(arg).unwrap())
+        .collect::<Array>();
+
+    match handler.apply(&JsValue::NULL, &args) {
+        Ok(value) => {
+            let promise = Promise::resolve(&value);
+            Box::pin(resolve_promise(promise))
+        }
+        Err(error) => ready(Err(anyhow!("`{}::{}` threw: {:?}", module, name, error))),
+    }
+}
+
+async fn call(module: String, name: String, args: Vec<VmValue>) -> VmResult<VmValue> {
+    let args = match args.iter().map(serde_json::to_value).collect::<Result<Vec<_>, _>>() {
+        Ok(args) => args,
+        Err(error) => return VmResult::panic(format!("{}::{}: {}", module, name, error)),
+    };
+
+    let session = vm_try!(session::current());
+    let path = format!("{}::{}", module, name);
+    let recorded = SerdeValue::Array(args.clone());
+    session.call(&path, recorded, |_| invoke(&module, &name, &args)).await
+}
+
+fn install(module: &mut Module, descriptor: &Descriptor) -> Result<(), ContextError> {
+    let item = [descriptor.name.as_str()];
+    let m = descriptor.module.clone();
+    let n = descriptor.name.clone();
+    let docs = descriptor.doc.as_deref().unwrap_or_default().lines();
+
+    match descriptor.arity {
+        0 => module.function(item, move || call(m.clone(), n.clone(), vec![]))?.docs(docs),
+        1 => module.function(item, move |a: VmValue| call(m.clone(), n.clone(), vec![a]))?.docs(docs),
+        2 => module.function(item, move |a: VmValue, b: VmValue| {
+            call(m.clone(), n.clone(), vec![a, b])
+        })?.docs(docs),
+        3 => module.function(item, move |a: VmValue, b: VmValue, c: VmValue| {
+            call(m.clone(), n.clone(), vec![a, b, c])
+        })?.docs(docs),
+        _ => module.function(item, move |a: VmValue, b: VmValue, c: VmValue, d: VmValue| {
+            call(m.clone(), n.clone(), vec![a, b, c, d])
+        })?.docs(docs),
+    };
+
+    Ok(())
+}
+
+/// Build one module per registered module name, leaving out functions with
+/// the write capability in read-only runs.
+pub(crate) fn modules(read_only: bool) -> Result<Vec<Module>, ContextError> {
+    let mut descriptors = descriptors();
+    descriptors.retain(|d| !read_only || d.capability == Capability::Read);
+    descriptors.sort_by(|a, b| a.module.cmp(&b.module));
+
+    let mut modules = Vec::<Module>::new();
+    let mut current = None::<String>;
+
+    for descriptor in &descriptors {
+        if current.as_deref() != Some(descriptor.module.as_str()) {
+            modules.push(Module::with_crate(&descriptor.module));
+            current = Some(descriptor.module.clone());
+        }
+
+        if let Some(module) = modules.last_mut() {
+            install(module, descriptor)?;
+        }
+    }
+
+    Ok(modules)
+}
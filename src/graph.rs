@@ -0,0 +1,245 @@
+//! The `cyb::graph` module: traversals over cyberlinks.
+//!
+//! Traversals run in Rust on top of the link queries, so exploring a few hops
+//! doesn't cost one script-level loop iteration per link. Every page fetched
+//! and every node expanded is still charged against the instruction budget.
+//! Link queries are cached for the duration of a single traversal.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use rune::runtime::{budget, VmResult};
+use rune::{vm_try, Any, ContextError, Module};
+
+use crate::cyb::links;
+use crate::host::Page;
+
+/// Links fetched per host call.
+const PAGE_SIZE: u64 = 100;
+
+/// Most neighbors considered per particle by `shortest_path`.
+const MAX_FANOUT: usize = 1000;
+
+/// A particle reached by a traversal.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct GraphNode {
+    /// CID of the particle.
+    #[rune(get)]
+    cid: String,
+    /// Number of hops from the start of the traversal.
+    #[rune(get, copy)]
+    depth: i64,
+    /// The particle it was reached from.
+    #[rune(get)]
+    parent: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Out,
+    In,
+    Both,
+}
+
+impl Direction {
+    fn parse(direction: &str) -> VmResult<Self> {
+        match direction {
+            "out" => VmResult::Ok(Self::Out),
+            "in" => VmResult::Ok(Self::In),
+            "both" => VmResult::Ok(Self::Both),
+            _ => VmResult::panic(format!(
+                "unknown direction `{}`, expected `out`, `in` or `both`",
+                direction
+            )),
+        }
+    }
+}
+
+fn charge() -> VmResult<()> {
+    if budget::take() {
+        VmResult::Ok(())
+    } else {
+        VmResult::panic("instruction budget exhausted during graph traversal")
+    }
+}
+
+fn limit(value: i64) -> usize {
+    usize::try_from(value).unwrap_or_default()
+}
+
+/// Link queries made by a single traversal, along with whether all links
+/// were fetched.
+#[derive(Default)]
+struct Graph {
+    cache: HashMap<(String, bool), (Vec<String>, bool)>,
+}
+
+impl Graph {
+    /// Distinct neighbors of `cid`, at most `fanout` of them.
+    async fn neighbors(&mut self, cid: &str, direction: Direction, fanout: usize) -> VmResult<Vec<String>> {
+        let mut neighbors = Vec::new();
+
+        if direction != Direction::In {
+            neighbors.extend(vm_try!(self.linked(cid, true, fanout).await));
+        }
+
+        if direction != Direction::Out {
+            neighbors.extend(vm_try!(self.linked(cid, false, fanout).await));
+        }
+
+        let mut seen = HashSet::new();
+        neighbors.retain(|neighbor| seen.insert(neighbor.clone()));
+        neighbors.truncate(fanout);
+        VmResult::Ok(neighbors)
+    }
+
+    /// Particles linked from (`outgoing`) or to `cid`.
+    async fn linked(&mut self, cid: &str, outgoing: bool, fanout: usize) -> VmResult<Vec<String>> {
+        let key = (cid.to_owned(), outgoing);
+
+        if let Some((cached, complete)) = self.cache.get(&key) {
+            if *complete || cached.len() >= fanout {
+                return VmResult::Ok(cached.clone());
+            }
+        }
+
+        let mut linked = Vec::new();
+        let mut complete = false;
+        let mut page = Page {
+            offset: 0,
+            limit: PAGE_SIZE,
+        };
+
+        loop {
+            vm_try!(charge());
+
+            let result = if outgoing {
+                links("cyber_links_from", cid, page, |host, page| host.cyber_links_from(cid, page)).await
+            } else {
+                links("cyber_links_to", cid, page, |host, page| host.cyber_links_to(cid, page)).await
+            };
+
+            let result = vm_try!(result);
+            let count = result.links.len();
+
+            linked.extend(result.links.into_iter().map(|link| if outgoing { link.to } else { link.from }));
+
+            // NB: a page without links, or whose next offset isn't past its
+            // own, is the last one, since paging on would never get anywhere.
+            let next = result
+                .next
+                .and_then(|next| u64::try_from(next).ok())
+                .filter(|&next| count > 0 && next > page.offset);
+
+            match next {
+                Some(next) if linked.len() < fanout => page.offset = next,
+                Some(_) => break,
+                None => {
+                    complete = true;
+                    break;
+                }
+            }
+        }
+
+        self.cache.insert(key, (linked.clone(), complete));
+        VmResult::Ok(linked)
+    }
+}
+
+/// Breadth-first walk from `cid` up to `depth` hops, returning at most `limit`
+/// particles in the order they were reached.
+async fn walk(cid: &str, depth: i64, direction: &str, limit: i64) -> VmResult<Vec<GraphNode>> {
+    let direction = vm_try!(Direction::parse(direction));
+    let limit = self::limit(limit);
+
+    let mut graph = Graph::default();
+    let mut seen = HashSet::from([cid.to_owned()]);
+    let mut queue = VecDeque::from([(cid.to_owned(), 0)]);
+    let mut nodes = Vec::new();
+
+    while let Some((current, current_depth)) = queue.pop_front() {
+        if current_depth >= depth || nodes.len() >= limit {
+            continue;
+        }
+
+        vm_try!(charge());
+
+        for neighbor in vm_try!(graph.neighbors(&current, direction, limit).await) {
+            if nodes.len() >= limit {
+                break;
+            }
+
+            if seen.insert(neighbor.clone()) {
+                nodes.push(GraphNode {
+                    cid: neighbor.clone(),
+                    depth: current_depth + 1,
+                    parent: current.clone(),
+                });
+
+                queue.push_back((neighbor, current_depth + 1));
+            }
+        }
+    }
+
+    VmResult::Ok(nodes)
+}
+
+/// Distinct particles one hop away from `cid`, at most `limit` of them.
+async fn neighbors(cid: &str, direction: &str, limit: i64) -> VmResult<Vec<String>> {
+    let direction = vm_try!(Direction::parse(direction));
+    Graph::default().neighbors(cid, direction, self::limit(limit)).await
+}
+
+/// The shortest chain of outgoing links from `from` to `to`, as the list of
+/// CIDs along it, or `None` if there is none within `max_depth` hops.
+async fn shortest_path(from: &str, to: &str, max_depth: i64) -> VmResult<Option<Vec<String>>> {
+    if from == to {
+        return VmResult::Ok(Some(vec![from.to_owned()]));
+    }
+
+    let mut graph = Graph::default();
+    let mut parents = HashMap::<String, String>::new();
+    let mut queue = VecDeque::from([(from.to_owned(), 0)]);
+
+    while let Some((current, depth)) = queue.pop_front() {
+        if depth >= max_depth {
+            continue;
+        }
+
+        vm_try!(charge());
+
+        for neighbor in vm_try!(graph.neighbors(&current, Direction::Out, MAX_FANOUT).await) {
+            if neighbor == from || parents.contains_key(&neighbor) {
+                continue;
+            }
+
+            parents.insert(neighbor.clone(), current.clone());
+
+            if neighbor == to {
+                let mut path = vec![neighbor];
+
+                while let Some(parent) = parents.get(path.last().unwrap()) {
+                    path.push(parent.clone());
+                }
+
+                path.reverse();
+                return VmResult::Ok(Some(path));
+            }
+
+            queue.push_back((neighbor, depth + 1));
+        }
+    }
+
+    VmResult::Ok(None)
+}
+
+/// The `cyb::graph` module.
+pub fn module() -> Result<Module, ContextError> {
+    let mut module = Module::with_crate_item("cyb", ["graph"]);
+
+    module.ty::<GraphNode>()?;
+    module.function(["walk"], walk)?;
+    module.function(["neighbors"], neighbors)?;
+    module.function(["shortest_path"], shortest_path)?;
+
+    Ok(module)
+}
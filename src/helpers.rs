@@ -1,4 +1,6 @@
 use rune::runtime::{Shared, Object, VmResult, Value as VmValue, Vec as VmVec};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::Value as SerdeValue;
 use wasm_bindgen_futures::JsFuture;
 
@@ -39,16 +41,47 @@ pub fn map_params_to_vec(serde_value: &SerdeValue) -> Vec<VmValue> {
     }
 }
 
-pub async fn execute_promise<F>(f: F) -> VmResult<VmValue>
+/// Decode the JSON answer of the host function `name`.
+pub fn from_json<T>(name: &str, value: SerdeValue) -> VmResult<T>
 where
-    F: Fn() -> js_sys::Promise,
+    T: DeserializeOwned,
 {
-    let js_value = JsFuture::from(f()).await;
-    match  js_value {
-        Ok(js_value) => {
-            let v: SerdeValue = serde_wasm_bindgen::from_value(js_value).unwrap();
-            VmResult::Ok(map_to_rune_value(&v))
-        },
-        Err(_) => VmResult::Ok(VmValue::Unit),
+    match serde_json::from_value(value) {
+        Ok(value) => VmResult::Ok(value),
+        Err(error) => VmResult::panic(format!("{}: unexpected host response: {}", name, error)),
+    }
+}
+
+/// Decode an options object passed by a script to the function `name`.
+pub fn from_options<T, O>(name: &str, options: &O) -> VmResult<T>
+where
+    T: DeserializeOwned,
+    O: Serialize,
+{
+    let value = serde_json::to_value(options).and_then(serde_json::from_value);
+
+    match value {
+        Ok(value) => VmResult::Ok(value),
+        Err(error) => VmResult::panic(format!("{}: invalid options: {}", name, error)),
+    }
+}
+
+/// Wait for the promise of a host binding, a rejection resolving to null.
+pub async fn resolve_promise(promise: js_sys::Promise) -> anyhow::Result<SerdeValue> {
+    match JsFuture::from(promise).await {
+        Ok(js_value) => from_js(js_value),
+        Err(_) => Ok(SerdeValue::Null),
+    }
+}
+
+/// Wait for `promise`, failing if it rejects.
+pub async fn settle_promise(promise: js_sys::Promise) -> anyhow::Result<SerdeValue> {
+    match JsFuture::from(promise).await {
+        Ok(js_value) => from_js(js_value),
+        Err(error) => Err(anyhow::anyhow!("promise rejected: {:?}", error)),
     }
-}
\ No newline at end of file
+}
+
+fn from_js(js_value: wasm_bindgen::JsValue) -> anyhow::Result<SerdeValue> {
+    serde_wasm_bindgen::from_value(js_value).map_err(|error| anyhow::anyhow!("host returned a value that isn't JSON: {}", error))
+}
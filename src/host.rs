@@ -0,0 +1,408 @@
+//! Host backends serving the `cyb` module.
+//!
+//! The `cyb` module never talks to the outside world directly. Every search,
+//! link, passport, IPFS and LLM call goes through a [CybHost], so the
+//! same scripts can run in the browser against the JS bindings ([WasmHost]) or
+//! natively against an in-memory graph ([MemoryHost]).
+
+use std::future::Future;
+use std::pin::Pin;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as SerdeValue};
+
+pub use self::memory::MemoryHost;
+pub use self::wasm::WasmHost;
+
+mod memory;
+mod wasm;
+
+/// The future returned by host calls, resolving to the JSON value the host
+/// answered with.
+pub type HostFuture = Pin<Box<dyn Future<Output = anyhow::Result<SerdeValue>>>>;
+
+/// Pagination of list queries.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Page {
+    /// Number of entries to skip.
+    pub offset: u64,
+    /// Largest number of entries to return, at most 1000.
+    pub limit: u64,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: 20,
+        }
+    }
+}
+
+/// Ordering of search results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchOrder {
+    /// Highest cyberrank first.
+    #[default]
+    Rank,
+    /// Most recent first.
+    Time,
+}
+
+/// Options of a search query.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    /// Number of results to skip.
+    pub offset: u64,
+    /// Largest number of results to return, at most 1000.
+    pub limit: u64,
+    /// Zero-based page number, takes precedence over `offset` if set.
+    pub page: Option<u64>,
+    /// Ordering of the results.
+    pub order: SearchOrder,
+    /// Only return particles linked by one of these neurons, if not empty.
+    pub neurons: Vec<String>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        let page = Page::default();
+
+        Self {
+            offset: page.offset,
+            limit: page.limit,
+            page: None,
+            order: SearchOrder::default(),
+            neurons: Vec::new(),
+        }
+    }
+}
+
+/// Author of a chat message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+    /// The result of a tool call.
+    Tool,
+}
+
+/// A message of a chat with an LLM.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Who wrote the message.
+    pub role: ChatRole,
+    /// Text of the message.
+    #[serde(default)]
+    pub content: String,
+    /// Tools the assistant asked to call.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Id of the call a tool message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// A call of a tool requested by an LLM.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Id the result of the call is reported under.
+    #[serde(default)]
+    pub id: String,
+    /// Name of the tool.
+    pub name: String,
+    /// Arguments as an object keyed by parameter name or as a list.
+    #[serde(default)]
+    pub arguments: SerdeValue,
+}
+
+/// A script function offered to an LLM as a tool.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ToolSpec {
+    /// Name of the function, e.g. `search_notes` or `notes::add`.
+    pub name: String,
+    /// Doc comment of the function.
+    pub description: String,
+    /// Names of its parameters, in order.
+    pub parameters: Vec<String>,
+}
+
+/// Options of a chat completion, left to the host's defaults if unset.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatOptions {
+    /// Name of the model, e.g. `gpt-4o` or the name of a local model.
+    pub model: Option<String>,
+    /// Sampling temperature, between 0 and 2.
+    pub temperature: Option<f64>,
+    /// Largest number of tokens to generate.
+    pub max_tokens: Option<u64>,
+}
+
+/// A stream of chunks answered by the host, e.g. the tokens of an LLM
+/// response.
+pub trait HostStream {
+    /// The next chunk, resolving to a string or `null` once the stream is
+    /// over.
+    fn next(&mut self) -> HostFuture;
+}
+
+/// Services the `cyb` module calls out to.
+///
+/// Methods are named after the `cyb` functions they back. Methods with a
+/// default implementation were added later and fail as unsupported unless
+/// the host implements them.
+pub trait CybHost {
+    /// Write a log message.
+    fn log(&self, message: &str);
+
+    /// Receive output written by scripts as soon as it is written, on top of
+    /// the output returned with the result.
+    fn output(&self, chunk: &str) {
+        let _ = chunk;
+    }
+
+    /// Whether output is passed on to [CybHost::output] as it is written.
+    ///
+    /// Plain API keys are refused then: output written before a key is used
+    /// has already been passed on and can't be redacted.
+    fn streams_output(&self) -> bool {
+        false
+    }
+
+    /// Current wall-clock time in milliseconds since the epoch.
+    fn now(&self) -> i64;
+
+    /// Full-text search over the knowledge graph.
+    fn cyber_search(&self, query: &str) -> HostFuture;
+
+    /// Create a cyberlink between two particles.
+    fn cyber_link(&self, from_cid: &str, to_cid: &str) -> HostFuture;
+
+    /// Look up a passport by its nickname.
+    fn get_passport_by_nickname(&self, nickname: &str) -> HostFuture;
+
+    /// Fetch the text content of a particle.
+    fn get_text_from_ipfs(&self, cid: &str) -> HostFuture;
+
+    /// Add text content to IPFS, resolving to its CID.
+    fn add_content_to_ipfs(&self, content: &str) -> HostFuture;
+
+    /// Send a single prompt to the LLM.
+    fn open_ai_prompt(&self, prompt: &str, api_key: &str) -> HostFuture;
+
+    /// Continue a chat offering `tools` to the LLM, resolving in the shape of
+    /// [CybHost::llm_chat] with the calls the LLM requests as `tool_calls:
+    /// [{ id, name, arguments }]`.
+    fn llm_chat_tools(&self, messages: &[ChatMessage], tools: &[ToolSpec], options: &ChatOptions) -> HostFuture {
+        let _ = (messages, tools, options);
+        unsupported("llm_chat_tools")
+    }
+
+    /// Stream the answer to a chat chunk by chunk, in the shape of
+    /// [CybHost::llm_chat] otherwise.
+    ///
+    /// The default implementation streams the whole answer of
+    /// [CybHost::llm_chat] as a single chunk.
+    fn llm_stream(&self, messages: &[ChatMessage], options: &ChatOptions) -> Box<dyn HostStream> {
+        once(chat_content(self.llm_chat(messages, options)))
+    }
+
+    /// The value of the secret `name`, e.g. an API key, resolving to a string
+    /// or `null` if the host doesn't hold it.
+    ///
+    /// Secrets are resolved outside of recording and replay, so their values
+    /// never end up in a replay bundle.
+    fn secret(&self, name: &str) -> HostFuture {
+        let _ = name;
+        unsupported("secret")
+    }
+
+    /// Continue a chat with an LLM of the host's choosing, resolving to
+    /// `{ content, finish_reason, model, usage: { prompt_tokens,
+    /// completion_tokens, total_tokens } }` or to the content alone.
+    fn llm_chat(&self, messages: &[ChatMessage], options: &ChatOptions) -> HostFuture {
+        let _ = (messages, options);
+        unsupported("llm_chat")
+    }
+
+    /// Search with pagination, ordering and neuron filters, resolving to
+    /// `{ results: [{ cid, rank, content_type, neuron, timestamp }], total }`.
+    ///
+    /// Hosts may also resolve to a plain array of every result, which is then
+    /// filtered, ordered and paginated locally. This is what the default
+    /// implementation does on top of [CybHost::cyber_search].
+    fn cyber_search_page(&self, query: &str, options: &SearchOptions) -> HostFuture {
+        let _ = options;
+        self.cyber_search(query)
+    }
+
+    /// Cyberlinks going out of a particle, resolving to
+    /// `{ links: [{ from, to, neuron, height, timestamp }], total }`.
+    fn cyber_links_from(&self, cid: &str, page: &Page) -> HostFuture {
+        let _ = (cid, page);
+        unsupported("cyber_links_from")
+    }
+
+    /// Cyberlinks pointing to a particle, in the same shape as
+    /// [CybHost::cyber_links_from].
+    fn cyber_links_to(&self, cid: &str, page: &Page) -> HostFuture {
+        let _ = (cid, page);
+        unsupported("cyber_links_to")
+    }
+
+    /// Cyberrank of a particle, resolving to a number or a numeric string.
+    fn particle_rank(&self, cid: &str) -> HostFuture {
+        let _ = cid;
+        unsupported("particle_rank")
+    }
+
+    /// Statistics of a particle, resolving to
+    /// `{ rank, inbound, outbound, neurons: [..] }`.
+    fn particle_stats(&self, cid: &str) -> HostFuture {
+        let _ = cid;
+        unsupported("particle_stats")
+    }
+
+    /// Balances of a neuron, resolving to `[{ denom, amount }]` with amounts
+    /// as integers or integer strings of up to 128 bits.
+    fn neuron_balance(&self, address: &str) -> HostFuture {
+        let _ = address;
+        unsupported("neuron_balance")
+    }
+
+    /// Energy of a neuron, resolving to
+    /// `{ volts, amperes, bandwidth_remaining, bandwidth_max }`.
+    fn neuron_energy(&self, address: &str) -> HostFuture {
+        let _ = address;
+        unsupported("neuron_energy")
+    }
+
+    /// The passport owned by or proving `address`, resolving to `null` if
+    /// there is none.
+    fn passport_by_address(&self, address: &str) -> HostFuture {
+        let _ = address;
+        unsupported("passport_by_address")
+    }
+
+    /// The passport with the given token id, resolving to `null` if there is
+    /// none.
+    fn passport_by_token_id(&self, token_id: &str) -> HostFuture {
+        let _ = token_id;
+        unsupported("passport_by_token_id")
+    }
+
+    /// Passports using the particle `cid`, e.g. as their avatar, resolving to
+    /// a list of passports.
+    fn passports_by_particle(&self, cid: &str) -> HostFuture {
+        let _ = cid;
+        unsupported("passports_by_particle")
+    }
+
+    /// The neuron running the script, resolving to `{ address, passport }`.
+    fn me(&self) -> HostFuture {
+        unsupported("me")
+    }
+
+    /// Content of a particle, resolving to
+    /// `{ content_type, size, text, bytes: [..] }` or `null` if not found.
+    /// Either `text` or `bytes` may be left out.
+    ///
+    /// The default implementation wraps [CybHost::get_text_from_ipfs].
+    fn ipfs_get(&self, cid: &str) -> HostFuture {
+        text_content(self.get_text_from_ipfs(cid))
+    }
+
+    /// The value stored under `key` by the script `scope`, resolving to `null`
+    /// if there is none.
+    fn storage_get(&self, scope: &str, key: &str) -> HostFuture {
+        let _ = (scope, key);
+        unsupported("storage_get")
+    }
+
+    /// Store `value` under `key` for the script `scope`.
+    fn storage_set(&self, scope: &str, key: &str, value: &SerdeValue) -> HostFuture {
+        let _ = (scope, key, value);
+        unsupported("storage_set")
+    }
+
+    /// Remove the value stored under `key` by the script `scope`.
+    fn storage_delete(&self, scope: &str, key: &str) -> HostFuture {
+        let _ = (scope, key);
+        unsupported("storage_delete")
+    }
+
+    /// Keys stored by the script `scope`, resolving to a list of strings.
+    fn storage_list(&self, scope: &str) -> HostFuture {
+        let _ = scope;
+        unsupported("storage_list")
+    }
+
+    /// Metadata of a particle without its content, resolving to
+    /// `{ content_type, size }` or `null` if not found.
+    fn ipfs_stat(&self, cid: &str) -> HostFuture {
+        let _ = cid;
+        unsupported("ipfs_stat")
+    }
+
+    /// Run `func_name` of the script stored at `cid` with `params` on the host
+    /// side.
+    ///
+    /// Deprecated: nested scripts are run by the crate itself, this is only
+    /// called for scripts whose source [CybHost::get_text_from_ipfs] can't
+    /// find.
+    fn eval_script_from_ipfs(&self, cid: &str, func_name: &str, params: &SerdeValue) -> HostFuture {
+        let _ = (func_name, params);
+        ready(Err(anyhow!("script `{}` not found", cid)))
+    }
+}
+
+/// Wrap the answer of [CybHost::get_text_from_ipfs] into the shape of
+/// [CybHost::ipfs_get].
+pub fn text_content(text: HostFuture) -> HostFuture {
+    Box::pin(async move {
+        Ok(match text.await? {
+            SerdeValue::String(text) => json!({ "text": text }),
+            _ => SerdeValue::Null,
+        })
+    })
+}
+
+/// Reduce the answer of [CybHost::llm_chat] to its content.
+pub fn chat_content(answer: HostFuture) -> HostFuture {
+    Box::pin(async move {
+        Ok(match answer.await? {
+            SerdeValue::Object(mut answer) => answer.remove("content").unwrap_or_default(),
+            answer => answer,
+        })
+    })
+}
+
+struct Once(Option<HostFuture>);
+
+impl HostStream for Once {
+    fn next(&mut self) -> HostFuture {
+        self.0.take().unwrap_or_else(|| ready(Ok(SerdeValue::Null)))
+    }
+}
+
+/// A stream of the single chunk `chunk` resolves to.
+pub fn once(chunk: HostFuture) -> Box<dyn HostStream> {
+    Box::new(Once(Some(chunk)))
+}
+
+/// Fail a host call the host doesn't implement.
+pub fn unsupported(name: &str) -> HostFuture {
+    ready(Err(anyhow!("`{}` is not supported by this host", name)))
+}
+
+/// Wrap an already computed value into a [HostFuture].
+pub fn ready(result: anyhow::Result<SerdeValue>) -> HostFuture {
+    Box::pin(std::future::ready(result))
+}
@@ -0,0 +1,528 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as SerdeValue};
+
+use crate::host::{
+    ready, ChatMessage, ChatOptions, ChatRole, CybHost, HostFuture, HostStream, Page, ToolCall, ToolSpec,
+};
+
+/// A cyberlink stored by the [MemoryHost].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MemoryLink {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub neuron: String,
+    #[serde(default)]
+    pub height: i64,
+    #[serde(default)]
+    pub timestamp: Option<String>,
+}
+
+/// Chunks of a canned LLM answer streamed by the [MemoryHost].
+struct MemoryStream {
+    chunks: VecDeque<String>,
+}
+
+impl HostStream for MemoryStream {
+    fn next(&mut self) -> HostFuture {
+        ready(Ok(self.chunks.pop_front().map(SerdeValue::String).unwrap_or_default()))
+    }
+}
+
+/// Account state of a neuron stored by the [MemoryHost].
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryNeuron {
+    /// Balances as `{ denom, amount }` objects.
+    pub balances: Vec<SerdeValue>,
+    /// Energy as `{ volts, amperes, bandwidth_remaining, bandwidth_max }`.
+    pub energy: SerdeValue,
+}
+
+/// A host keeping its graph, IPFS store and passports in memory, for running
+/// scripts natively.
+///
+/// It can be deserialized from a JSON fixture:
+///
+/// ```json
+/// {
+///     "particles": { "Qm...": "hello" },
+///     "links": [{ "from": "Qm...", "to": "Qm...", "neuron": "bostrom1...", "height": 1 }],
+///     "passports": { "joe": { "token_id": "1", "owner": "bostrom1...", "avatar": "Qm..." } },
+///     "prompts": { "say hi": "hi" },
+///     "tool_calls": { "find notes": [{ "name": "search_notes", "arguments": { "query": "rune" } }] },
+///     "ranks": { "Qm...": 42.0 },
+///     "neurons": { "bostrom1...": { "balances": [{ "denom": "boot", "amount": "1000" }] } },
+///     "me": "bostrom1...",
+///     "storage": { "my-script": { "seen": ["Qm..."] } },
+///     "secrets": { "openai": "sk-..." },
+///     "now": 1684000000000
+/// }
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct MemoryHost {
+    /// Particle contents keyed by CID.
+    particles: RefCell<HashMap<String, String>>,
+    /// Cyberlinks in the order they were added.
+    links: RefCell<Vec<MemoryLink>>,
+    /// Passports keyed by nickname.
+    passports: HashMap<String, SerdeValue>,
+    /// Canned LLM answers keyed by prompt, or by the last user message of a
+    /// chat.
+    prompts: HashMap<String, String>,
+    /// Tool calls requested before answering, keyed by prompt.
+    tool_calls: HashMap<String, Vec<ToolCall>>,
+    /// Cyberranks keyed by CID, the number of inbound links is used for
+    /// particles without one.
+    ranks: HashMap<String, f64>,
+    /// Account state of neurons keyed by address.
+    neurons: HashMap<String, MemoryNeuron>,
+    /// Address of the neuron running scripts.
+    me: Option<String>,
+    /// Storage entries keyed by script identity.
+    storage: RefCell<HashMap<String, BTreeMap<String, SerdeValue>>>,
+    /// Secret values keyed by name.
+    secrets: HashMap<String, String>,
+    /// Pinned wall-clock time, the system clock is used if unset.
+    now: Option<i64>,
+    /// Messages logged by scripts.
+    #[serde(skip)]
+    logs: RefCell<Vec<String>>,
+    /// Output passed on as it is written, if streaming is turned on.
+    #[serde(skip)]
+    streamed: Option<RefCell<String>>,
+}
+
+impl MemoryHost {
+    /// Construct an empty host.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a particle under the given CID.
+    pub fn add_particle(&mut self, cid: impl Into<String>, content: impl Into<String>) {
+        self.particles.get_mut().insert(cid.into(), content.into());
+    }
+
+    /// Add a cyberlink.
+    pub fn add_link(&mut self, link: MemoryLink) {
+        self.links.get_mut().push(link);
+    }
+
+    /// Register a passport under its nickname.
+    pub fn add_passport(&mut self, nickname: impl Into<String>, passport: SerdeValue) {
+        self.passports.insert(nickname.into(), passport);
+    }
+
+    /// Answer `prompt` with `answer`.
+    pub fn add_prompt(&mut self, prompt: impl Into<String>, answer: impl Into<String>) {
+        self.prompts.insert(prompt.into(), answer.into());
+    }
+
+    /// Request `calls` when `prompt` is sent along with tools, before
+    /// answering it.
+    pub fn add_tool_calls(&mut self, prompt: impl Into<String>, calls: Vec<ToolCall>) {
+        self.tool_calls.insert(prompt.into(), calls);
+    }
+
+    /// Messages logged by scripts so far.
+    pub fn logs(&self) -> Vec<String> {
+        self.logs.borrow().clone()
+    }
+
+    /// Take output as it is written, like the `jsOnOutput` binding.
+    pub fn stream_output(&mut self) {
+        self.streamed = Some(RefCell::new(String::new()));
+    }
+
+    /// Output passed on as it was written, if streaming is turned on.
+    pub fn streamed_output(&self) -> Option<String> {
+        self.streamed.as_ref().map(|streamed| streamed.borrow().clone())
+    }
+
+    /// Set the cyberrank of a particle.
+    pub fn set_rank(&mut self, cid: impl Into<String>, rank: f64) {
+        self.ranks.insert(cid.into(), rank);
+    }
+
+    /// Set the account state of a neuron.
+    pub fn add_neuron(&mut self, address: impl Into<String>, neuron: MemoryNeuron) {
+        self.neurons.insert(address.into(), neuron);
+    }
+
+    /// Set the address of the neuron running scripts.
+    pub fn set_me(&mut self, address: impl Into<String>) {
+        self.me = Some(address.into());
+    }
+
+    /// Store `value` under `key` for the script `scope`.
+    pub fn set_storage(&mut self, scope: impl Into<String>, key: impl Into<String>, value: SerdeValue) {
+        self.storage.get_mut().entry(scope.into()).or_default().insert(key.into(), value);
+    }
+
+    /// Entries stored by the script `scope`.
+    pub fn storage(&self, scope: &str) -> BTreeMap<String, SerdeValue> {
+        self.storage.borrow().get(scope).cloned().unwrap_or_default()
+    }
+
+    /// Hold the secret `name`.
+    pub fn add_secret(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.secrets.insert(name.into(), value.into());
+    }
+
+    /// Pin the wall-clock time returned by the host.
+    pub fn set_now(&mut self, now: i64) {
+        self.now = Some(now);
+    }
+
+    /// Cyberlinks added so far, including those created by scripts.
+    pub fn links(&self) -> Vec<MemoryLink> {
+        self.links.borrow().clone()
+    }
+
+    fn links_page<F>(&self, filter: F, page: &Page) -> SerdeValue
+    where
+        F: Fn(&MemoryLink) -> bool,
+    {
+        let all = self.links.borrow();
+        let matching = all.iter().filter(|link| filter(link)).collect::<Vec<_>>();
+
+        let total = matching.len();
+
+        let links = matching
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(page.limit as usize)
+            .collect::<Vec<_>>();
+
+        json!({ "links": links, "total": total })
+    }
+
+    /// Passports matching `filter`, in nickname order.
+    fn passports<F>(&self, filter: F) -> Vec<SerdeValue>
+    where
+        F: Fn(&SerdeValue) -> bool,
+    {
+        let mut passports = self
+            .passports
+            .iter()
+            .filter(|(_, passport)| filter(passport))
+            .collect::<Vec<_>>();
+
+        passports.sort_by(|a, b| a.0.cmp(b.0));
+        passports.into_iter().map(|(_, passport)| passport.clone()).collect()
+    }
+
+    fn rank(&self, cid: &str) -> f64 {
+        match self.ranks.get(cid) {
+            Some(rank) => *rank,
+            None => self.links.borrow().iter().filter(|link| link.to == cid).count() as f64,
+        }
+    }
+}
+
+impl CybHost for MemoryHost {
+    fn log(&self, message: &str) {
+        self.logs.borrow_mut().push(message.to_owned());
+    }
+
+    fn streams_output(&self) -> bool {
+        self.streamed.is_some()
+    }
+
+    fn output(&self, chunk: &str) {
+        if let Some(streamed) = &self.streamed {
+            streamed.borrow_mut().push_str(chunk);
+        }
+    }
+
+    fn now(&self) -> i64 {
+        self.now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default()
+        })
+    }
+
+    fn cyber_search(&self, query: &str) -> HostFuture {
+        let query = query.to_lowercase();
+
+        let mut results = self
+            .particles
+            .borrow()
+            .iter()
+            .map(|(cid, content)| (cid.clone(), content.to_lowercase().matches(&query).count()))
+            .filter(|(_, count)| *count > 0)
+            .collect::<Vec<_>>();
+
+        results.sort();
+
+        let results = results
+            .into_iter()
+            .map(|(cid, count)| json!({ "cid": cid, "rank": count, "content_type": "text/plain" }))
+            .collect();
+
+        ready(Ok(SerdeValue::Array(results)))
+    }
+
+    fn cyber_link(&self, from_cid: &str, to_cid: &str) -> HostFuture {
+        let mut links = self.links.borrow_mut();
+        let height = links.iter().map(|link| link.height).max().unwrap_or_default() + 1;
+
+        links.push(MemoryLink {
+            from: from_cid.to_owned(),
+            to: to_cid.to_owned(),
+            neuron: String::new(),
+            height,
+            timestamp: None,
+        });
+
+        ready(Ok(SerdeValue::Null))
+    }
+
+    fn get_passport_by_nickname(&self, nickname: &str) -> HostFuture {
+        let passport = self.passports.get(nickname).cloned();
+        ready(Ok(passport.unwrap_or_default()))
+    }
+
+    fn get_text_from_ipfs(&self, cid: &str) -> HostFuture {
+        let content = self.particles.borrow().get(cid).cloned();
+        ready(Ok(content.map(SerdeValue::String).unwrap_or_default()))
+    }
+
+    fn add_content_to_ipfs(&self, content: &str) -> HostFuture {
+        let cid = crate::cid::of(content.as_bytes());
+
+        self.particles
+            .borrow_mut()
+            .insert(cid.clone(), content.to_owned());
+
+        ready(Ok(SerdeValue::String(cid)))
+    }
+
+    fn open_ai_prompt(&self, prompt: &str, _: &str) -> HostFuture {
+        let answer = self.prompts.get(prompt).cloned();
+        ready(Ok(answer.map(SerdeValue::String).unwrap_or_default()))
+    }
+
+    fn llm_chat(&self, messages: &[ChatMessage], options: &ChatOptions) -> HostFuture {
+        let prompt = messages.iter().rev().find(|m| m.role == ChatRole::User);
+
+        let Some(answer) = prompt.and_then(|m| self.prompts.get(&m.content)) else {
+            return ready(Ok(SerdeValue::Null));
+        };
+
+        // NB: words stand in for tokens.
+        let prompt_tokens = messages.iter().map(|m| m.content.split_whitespace().count()).sum::<usize>();
+        let completion_tokens = answer.split_whitespace().count();
+
+        ready(Ok(json!({
+            "content": answer,
+            "finish_reason": "stop",
+            "model": options.model.as_deref().unwrap_or("memory"),
+            "usage": {
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": completion_tokens,
+                "total_tokens": prompt_tokens + completion_tokens,
+            },
+        })))
+    }
+
+    fn llm_chat_tools(&self, messages: &[ChatMessage], _: &[ToolSpec], options: &ChatOptions) -> HostFuture {
+        // NB: the calls are requested right after the prompt, the answer once
+        // their results are in.
+        if let Some(last) = messages.last().filter(|m| m.role == ChatRole::User) {
+            if let Some(calls) = self.tool_calls.get(&last.content) {
+                let calls = calls
+                    .iter()
+                    .enumerate()
+                    .map(|(i, call)| ToolCall {
+                        id: format!("call-{}", i),
+                        ..call.clone()
+                    })
+                    .collect::<Vec<_>>();
+
+                return ready(Ok(json!({ "tool_calls": calls, "finish_reason": "tool_calls" })));
+            }
+        }
+
+        self.llm_chat(messages, options)
+    }
+
+    fn llm_stream(&self, messages: &[ChatMessage], _: &ChatOptions) -> Box<dyn HostStream> {
+        let prompt = messages.iter().rev().find(|m| m.role == ChatRole::User);
+        let answer = prompt.and_then(|m| self.prompts.get(&m.content));
+
+        // NB: words stand in for tokens.
+        let chunks = answer
+            .map(|answer| answer.split_inclusive(' ').map(str::to_owned).collect())
+            .unwrap_or_default();
+
+        Box::new(MemoryStream { chunks })
+    }
+
+    fn secret(&self, name: &str) -> HostFuture {
+        let value = self.secrets.get(name).cloned();
+        ready(Ok(value.map(SerdeValue::String).unwrap_or_default()))
+    }
+
+    fn cyber_links_from(&self, cid: &str, page: &Page) -> HostFuture {
+        ready(Ok(self.links_page(|link| link.from == cid, page)))
+    }
+
+    fn cyber_links_to(&self, cid: &str, page: &Page) -> HostFuture {
+        ready(Ok(self.links_page(|link| link.to == cid, page)))
+    }
+
+    fn particle_rank(&self, cid: &str) -> HostFuture {
+        ready(Ok(json!(self.rank(cid))))
+    }
+
+    fn particle_stats(&self, cid: &str) -> HostFuture {
+        let links = self.links.borrow();
+        let inbound = links.iter().filter(|link| link.to == cid).collect::<Vec<_>>();
+        let outbound = links.iter().filter(|link| link.from == cid).count();
+
+        let mut neurons = inbound.iter().map(|link| link.neuron.clone()).collect::<Vec<_>>();
+        neurons.sort();
+        neurons.dedup();
+
+        ready(Ok(json!({
+            "cid": cid,
+            "rank": self.rank(cid),
+            "inbound": inbound.len(),
+            "outbound": outbound,
+            "neurons": neurons,
+        })))
+    }
+
+    fn neuron_balance(&self, address: &str) -> HostFuture {
+        let balances = self.neurons.get(address).map(|n| n.balances.clone());
+        ready(Ok(json!(balances.unwrap_or_default())))
+    }
+
+    fn neuron_energy(&self, address: &str) -> HostFuture {
+        let energy = self.neurons.get(address).map(|n| n.energy.clone());
+        ready(Ok(energy.filter(|e| !e.is_null()).unwrap_or_else(|| json!({}))))
+    }
+
+    fn passport_by_address(&self, address: &str) -> HostFuture {
+        let passport = self.passports(|passport| passport_has_address(passport, address)).into_iter().next();
+        ready(Ok(passport.unwrap_or_default()))
+    }
+
+    fn passport_by_token_id(&self, token_id: &str) -> HostFuture {
+        let passport = self
+            .passports(|passport| match &passport_field(passport, "token_id") {
+                SerdeValue::String(id) => id == token_id,
+                SerdeValue::Number(id) => id.to_string() == token_id,
+                _ => false,
+            })
+            .into_iter()
+            .next();
+
+        ready(Ok(passport.unwrap_or_default()))
+    }
+
+    fn passports_by_particle(&self, cid: &str) -> HostFuture {
+        let passports = self.passports(|passport| {
+            passport_field(passport, "avatar") == cid || passport_field(passport, "particle") == cid
+        });
+
+        ready(Ok(SerdeValue::Array(passports)))
+    }
+
+    fn me(&self) -> HostFuture {
+        let me = match &self.me {
+            Some(address) => address,
+            None => return ready(Ok(SerdeValue::Null)),
+        };
+
+        let passport = self.passports(|passport| passport["owner"] == **me).into_iter().next();
+
+        ready(Ok(json!({ "address": me, "passport": passport })))
+    }
+
+    fn ipfs_get(&self, cid: &str) -> HostFuture {
+        let content = self.particles.borrow().get(cid).map(|content| {
+            json!({
+                "content_type": content_type(content),
+                "size": content.len(),
+                "text": content,
+            })
+        });
+
+        ready(Ok(content.unwrap_or_default()))
+    }
+
+    fn storage_get(&self, scope: &str, key: &str) -> HostFuture {
+        let value = self.storage.borrow().get(scope).and_then(|entries| entries.get(key).cloned());
+        ready(Ok(value.unwrap_or_default()))
+    }
+
+    fn storage_set(&self, scope: &str, key: &str, value: &SerdeValue) -> HostFuture {
+        self.storage
+            .borrow_mut()
+            .entry(scope.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value.clone());
+
+        ready(Ok(SerdeValue::Null))
+    }
+
+    fn storage_delete(&self, scope: &str, key: &str) -> HostFuture {
+        if let Some(entries) = self.storage.borrow_mut().get_mut(scope) {
+            entries.remove(key);
+        }
+
+        ready(Ok(SerdeValue::Null))
+    }
+
+    fn storage_list(&self, scope: &str) -> HostFuture {
+        let keys = self.storage(scope).into_keys().collect::<Vec<_>>();
+        ready(Ok(json!(keys)))
+    }
+
+    fn ipfs_stat(&self, cid: &str) -> HostFuture {
+        let stat = self.particles.borrow().get(cid).map(|content| {
+            json!({ "content_type": content_type(content), "size": content.len() })
+        });
+
+        ready(Ok(stat.unwrap_or_default()))
+    }
+}
+
+/// Content type of a stored particle.
+fn content_type(content: &str) -> &'static str {
+    match serde_json::from_str::<SerdeValue>(content) {
+        Ok(SerdeValue::Object(_) | SerdeValue::Array(_)) => "application/json",
+        _ => "text/plain",
+    }
+}
+
+/// A field of a passport fixture, either inlined or in its `extension`.
+fn passport_field(passport: &SerdeValue, field: &str) -> SerdeValue {
+    match &passport[field] {
+        SerdeValue::Null => passport["extension"][field].clone(),
+        value => value.clone(),
+    }
+}
+
+fn passport_has_address(passport: &SerdeValue, address: &str) -> bool {
+    if passport["owner"] == address {
+        return true;
+    }
+
+    match passport_field(passport, "addresses") {
+        SerdeValue::Array(addresses) => addresses
+            .iter()
+            .any(|a| *a == address || a["address"] == address),
+        _ => false,
+    }
+}
@@ -0,0 +1,296 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail};
+use gloo_utils::format::JsValueSerdeExt;
+use js_sys::{Array, Function, Object, Promise, Reflect};
+use serde_json::Value as SerdeValue;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::helpers::{resolve_promise, settle_promise};
+use crate::host::{
+    chat_content, once, ready, text_content, ChatMessage, ChatOptions, CybHost, HostFuture, HostStream, Page,
+    SearchOptions, ToolSpec,
+};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console)]
+    fn log(s: &str);
+}
+
+/// Functions every bindings object has to provide.
+const REQUIRED_BINDINGS: &[&str] = &[
+    "jsCyberSearch",
+    "jsCyberLink",
+    "jsGetPassportByNickname",
+    "jsGetIpfsTextContent",
+    "jsAddContenToIpfs",
+    "jsPromptToOpenAI",
+];
+
+thread_local! {
+    static INSTALLED: RefCell<Option<Rc<WasmHost>>> = RefCell::new(None);
+}
+
+/// The host provided by the embedding app through a JS bindings object.
+///
+/// Each binding is a function returning a promise (or a plain value). A
+/// rejected promise resolves to `null`.
+pub struct WasmHost {
+    bindings: Object,
+}
+
+impl WasmHost {
+    /// Construct a host from a bindings object, checking that every required
+    /// function is present.
+    pub fn new(bindings: JsValue) -> anyhow::Result<Self> {
+        let bindings = match bindings.dyn_into::<Object>() {
+            Ok(bindings) => bindings,
+            Err(_) => bail!("host bindings must be an object"),
+        };
+
+        let missing = REQUIRED_BINDINGS
+            .iter()
+            .filter(|name| function(&bindings, name).is_none())
+            .copied()
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            bail!("host bindings are missing required functions: {}", missing.join(", "));
+        }
+
+        Ok(Self { bindings })
+    }
+
+    /// Install this host as the one used by `compile`.
+    pub(crate) fn install(self) {
+        INSTALLED.with(|installed| *installed.borrow_mut() = Some(Rc::new(self)));
+    }
+
+    /// The host installed with `init_bindings`.
+    pub(crate) fn installed() -> anyhow::Result<Rc<Self>> {
+        INSTALLED.with(|installed| match &*installed.borrow() {
+            Some(host) => Ok(host.clone()),
+            None => bail!("host bindings are not initialized, call `init_bindings(bindings)` first"),
+        })
+    }
+
+    /// Call the binding `name` with `args`.
+    fn call(&self, name: &str, args: &[JsValue]) -> HostFuture {
+        let function = match function(&self.bindings, name) {
+            Some(function) => function,
+            None => return ready(Err(anyhow!("host binding `{}` is not provided", name))),
+        };
+
+        let args = args.iter().collect::<Array>();
+
+        match function.apply(&JsValue::NULL, &args) {
+            Ok(value) => {
+                let promise = Promise::resolve(&value);
+                Box::pin(resolve_promise(promise))
+            }
+            Err(error) => ready(Err(anyhow!("host binding `{}` threw: {:?}", name, error))),
+        }
+    }
+}
+
+/// An async iterator returned by a binding, e.g. an async generator.
+struct WasmStream {
+    iterator: JsValue,
+}
+
+impl HostStream for WasmStream {
+    fn next(&mut self) -> HostFuture {
+        let next = match function(&self.iterator, "next") {
+            Some(next) => next,
+            None => return ready(Err(anyhow!("stream has no `next` method"))),
+        };
+
+        match next.call0(&self.iterator) {
+            Ok(value) => {
+                let promise = Promise::resolve(&value);
+
+                // NB: unlike other bindings, a rejection fails the stream
+                // rather than ending it.
+                Box::pin(async move {
+                    let result = settle_promise(promise).await?;
+
+                    // NB: `{ value, done }`, as returned by async iterators.
+                    Ok(match result.get("done").and_then(SerdeValue::as_bool) {
+                        Some(true) => SerdeValue::Null,
+                        _ => result.get("value").cloned().unwrap_or_default(),
+                    })
+                })
+            }
+            Err(error) => ready(Err(anyhow!("stream threw: {:?}", error))),
+        }
+    }
+}
+
+fn function(bindings: &JsValue, name: &str) -> Option<Function> {
+    Reflect::get(bindings, &JsValue::from_str(name))
+        .ok()?
+        .dyn_into::<Function>()
+        .ok()
+}
+
+impl CybHost for WasmHost {
+    fn log(&self, message: &str) {
+        log(message);
+    }
+
+    fn streams_output(&self) -> bool {
+        function(&self.bindings, "jsOnOutput").is_some()
+    }
+
+    fn output(&self, chunk: &str) {
+        if let Some(function) = function(&self.bindings, "jsOnOutput") {
+            let _ = function.call1(&JsValue::NULL, &chunk.into());
+        }
+    }
+
+    fn now(&self) -> i64 {
+        js_sys::Date::now() as i64
+    }
+
+    fn cyber_search(&self, query: &str) -> HostFuture {
+        self.call("jsCyberSearch", &[query.into()])
+    }
+
+    fn cyber_link(&self, from_cid: &str, to_cid: &str) -> HostFuture {
+        self.call("jsCyberLink", &[from_cid.into(), to_cid.into()])
+    }
+
+    fn get_passport_by_nickname(&self, nickname: &str) -> HostFuture {
+        self.call("jsGetPassportByNickname", &[nickname.into()])
+    }
+
+    fn get_text_from_ipfs(&self, cid: &str) -> HostFuture {
+        self.call("jsGetIpfsTextContent", &[cid.into()])
+    }
+
+    fn eval_script_from_ipfs(&self, cid: &str, func_name: &str, params: &SerdeValue) -> HostFuture {
+        if function(&self.bindings, "jsEvalScriptFromIpfs").is_none() {
+            return ready(Err(anyhow!("script `{}` not found", cid)));
+        }
+
+        let js_value = <JsValue as JsValueSerdeExt>::from_serde(params).unwrap();
+        self.call("jsEvalScriptFromIpfs", &[cid.into(), func_name.into(), js_value])
+    }
+
+    fn add_content_to_ipfs(&self, content: &str) -> HostFuture {
+        self.call("jsAddContenToIpfs", &[content.into()])
+    }
+
+    fn open_ai_prompt(&self, prompt: &str, api_key: &str) -> HostFuture {
+        self.call("jsPromptToOpenAI", &[prompt.into(), api_key.into()])
+    }
+
+    fn cyber_search_page(&self, query: &str, options: &SearchOptions) -> HostFuture {
+        let options = <JsValue as JsValueSerdeExt>::from_serde(options).unwrap();
+        self.call("jsCyberSearch", &[query.into(), options])
+    }
+
+    fn cyber_links_from(&self, cid: &str, page: &Page) -> HostFuture {
+        let page = <JsValue as JsValueSerdeExt>::from_serde(page).unwrap();
+        self.call("jsCyberLinksFrom", &[cid.into(), page])
+    }
+
+    fn cyber_links_to(&self, cid: &str, page: &Page) -> HostFuture {
+        let page = <JsValue as JsValueSerdeExt>::from_serde(page).unwrap();
+        self.call("jsCyberLinksTo", &[cid.into(), page])
+    }
+
+    fn particle_rank(&self, cid: &str) -> HostFuture {
+        self.call("jsParticleRank", &[cid.into()])
+    }
+
+    fn particle_stats(&self, cid: &str) -> HostFuture {
+        self.call("jsParticleStats", &[cid.into()])
+    }
+
+    fn neuron_balance(&self, address: &str) -> HostFuture {
+        self.call("jsNeuronBalance", &[address.into()])
+    }
+
+    fn neuron_energy(&self, address: &str) -> HostFuture {
+        self.call("jsNeuronEnergy", &[address.into()])
+    }
+
+    fn passport_by_address(&self, address: &str) -> HostFuture {
+        self.call("jsGetPassportByAddress", &[address.into()])
+    }
+
+    fn passport_by_token_id(&self, token_id: &str) -> HostFuture {
+        self.call("jsGetPassportByTokenId", &[token_id.into()])
+    }
+
+    fn passports_by_particle(&self, cid: &str) -> HostFuture {
+        self.call("jsGetPassportsByParticle", &[cid.into()])
+    }
+
+    fn me(&self) -> HostFuture {
+        self.call("jsGetMe", &[])
+    }
+
+    fn ipfs_get(&self, cid: &str) -> HostFuture {
+        match function(&self.bindings, "jsIpfsGet") {
+            Some(_) => self.call("jsIpfsGet", &[cid.into()]),
+            None => text_content(self.get_text_from_ipfs(cid)),
+        }
+    }
+
+    fn ipfs_stat(&self, cid: &str) -> HostFuture {
+        self.call("jsIpfsStat", &[cid.into()])
+    }
+
+    fn llm_chat(&self, messages: &[ChatMessage], options: &ChatOptions) -> HostFuture {
+        let messages = <JsValue as JsValueSerdeExt>::from_serde(messages).unwrap();
+        let options = <JsValue as JsValueSerdeExt>::from_serde(options).unwrap();
+        self.call("jsLlmChat", &[messages, options])
+    }
+
+    fn llm_chat_tools(&self, messages: &[ChatMessage], tools: &[ToolSpec], options: &ChatOptions) -> HostFuture {
+        let messages = <JsValue as JsValueSerdeExt>::from_serde(messages).unwrap();
+        let tools = <JsValue as JsValueSerdeExt>::from_serde(tools).unwrap();
+        let options = <JsValue as JsValueSerdeExt>::from_serde(options).unwrap();
+        self.call("jsLlmChatTools", &[messages, tools, options])
+    }
+
+    fn llm_stream(&self, messages: &[ChatMessage], options: &ChatOptions) -> Box<dyn HostStream> {
+        let Some(function) = function(&self.bindings, "jsLlmStream") else {
+            return once(chat_content(self.llm_chat(messages, options)));
+        };
+
+        let messages = <JsValue as JsValueSerdeExt>::from_serde(messages).unwrap();
+        let options = <JsValue as JsValueSerdeExt>::from_serde(options).unwrap();
+
+        match function.call2(&JsValue::NULL, &messages, &options) {
+            Ok(iterator) => Box::new(WasmStream { iterator }),
+            Err(error) => once(ready(Err(anyhow!("host binding `jsLlmStream` threw: {:?}", error)))),
+        }
+    }
+
+    fn secret(&self, name: &str) -> HostFuture {
+        self.call("jsGetSecret", &[name.into()])
+    }
+
+    fn storage_get(&self, scope: &str, key: &str) -> HostFuture {
+        self.call("jsStorageGet", &[scope.into(), key.into()])
+    }
+
+    fn storage_set(&self, scope: &str, key: &str, value: &SerdeValue) -> HostFuture {
+        let value = <JsValue as JsValueSerdeExt>::from_serde(value).unwrap();
+        self.call("jsStorageSet", &[scope.into(), key.into(), value])
+    }
+
+    fn storage_delete(&self, scope: &str, key: &str) -> HostFuture {
+        self.call("jsStorageDelete", &[scope.into(), key.into()])
+    }
+
+    fn storage_list(&self, scope: &str) -> HostFuture {
+        self.call("jsStorageList", &[scope.into()])
+    }
+}
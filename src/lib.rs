@@ -29,10 +29,17 @@
 #![allow(clippy::unused_unit)]
 
 use std::fmt;
+use std::io::Write as _;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use anyhow::Context as _;
+use cache::CachedUnit;
+use context::RunContext;
+use deterministic::Deterministic;
+use docs::{DocVisitor, Docs};
 use gloo_utils::format::JsValueSerdeExt;
+use host::{CybHost, WasmHost};
 use helpers::{map_to_rune_value,map_params_to_vec};
 use rune::ast::Spanned;
 use rune::compile::LinkerError;
@@ -43,9 +50,28 @@ use rune::{Context, ContextError, Options};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 use serde_json::Value as SerdeValue;
+use session::{Frame, Program, ReplayBundle, Session, REPLAY_VERSION};
 
+mod agent;
+mod bytecode;
+mod cache;
+mod cid;
+mod context;
 mod cyb;
+mod deterministic;
+mod docs;
+mod eval;
+mod extensions;
+mod graph;
 mod helpers;
+mod llm;
+mod profile;
+pub mod host;
+mod session;
+mod storage;
+mod types;
+
+pub use cache::CacheStats;
 
 // Next let's define a macro that's like `println!`, only it works for
 // `console.log`. Note that `println!` doesn't actually work on the wasm target
@@ -55,17 +81,22 @@ mod helpers;
 //     ($($t:tt)*) => (cyb::log(&format_args!($($t)*).to_string()))
 // }
 
+/// Parameters of a run, deserialized from the same JSON the JS side passes to
+/// [compile].
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct CompilerParams {
+pub struct CompilerParams {
     read_only: bool,
+    /// Identity of the script, scoping its `cyb::storage` entries.
+    #[serde(default)]
+    script_id: Option<String>,
     func_name: String,
     func_params: SerdeValue,
     execute: bool,
     config: Config
 }
 
-#[derive(Default, Serialize)]
+#[derive(Clone, Default, Serialize)]
 struct WasmPosition {
     line: u32,
     character: u32,
@@ -81,7 +112,7 @@ impl From<(usize, usize)> for WasmPosition {
 }
 
 #[derive(Deserialize)]
-struct Config {
+pub struct Config {
     /// Budget.
     #[serde(default)]
     budget: Option<usize>,
@@ -97,9 +128,35 @@ struct Config {
     /// Suppress text warnings.
     #[serde(default)]
     suppress_text_warnings: bool,
+    /// Run deterministically: seeded randomness, pinned clock and host calls
+    /// served from a snapshot.
+    #[serde(default)]
+    deterministic: Option<Deterministic>,
+    /// Record every host call, along with the run params and the seed of
+    /// `rand`, into a replay bundle returned with the result. Like replaying
+    /// runs, recording runs have a seeded `rand` and no `http`.
+    #[serde(default)]
+    record: bool,
+    /// Answer host calls from a previously recorded replay bundle instead of
+    /// calling the host. The bundle's function, parameters, run params and
+    /// seed are used, and the sources and settings have to match.
+    #[serde(default)]
+    replay: Option<ReplayBundle>,
+    /// Largest nesting depth of scripts evaluated with
+    /// `cyb::eval_script_from_ipfs`, 8 by default.
+    #[serde(default)]
+    max_eval_depth: Option<usize>,
 }
 
-#[derive(Serialize)]
+impl Config {
+    /// Whether runs are sealed (deterministic, recording or replaying), see
+    /// [setup_context]. Units compiled for sealed and other runs differ.
+    fn sealed(&self) -> bool {
+        self.deterministic.is_some() || self.record || self.replay.is_some()
+    }
+}
+
+#[derive(Clone, Serialize)]
 enum WasmDiagnosticKind {
     #[serde(rename = "error")]
     Error,
@@ -107,7 +164,7 @@ enum WasmDiagnosticKind {
     Warning,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 struct WasmDiagnostic {
     kind: WasmDiagnosticKind,
     start: WasmPosition,
@@ -124,12 +181,38 @@ pub struct WasmCompileResult {
     result: Option<String>,
     output: Option<String>,
     instructions: Option<String>,
+    replay: Option<ReplayBundle>,
 }
 
 impl WasmCompileResult {
+    /// The error the run failed with, if any.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Diagnostics rendered as text.
+    pub fn diagnostics_output(&self) -> Option<&str> {
+        self.diagnostics_output.as_deref()
+    }
+
+    /// Debug representation of the value returned by the script.
+    pub fn result(&self) -> Option<&str> {
+        self.result.as_deref()
+    }
+
+    /// Output captured from the script.
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    /// Dumped instructions, if requested.
+    pub fn instructions(&self) -> Option<&str> {
+        self.instructions.as_deref()
+    }
+
     /// Construct output from compile result.
-    fn output(
-        io: &CaptureIo,
+    fn from_output(
+        captured: Option<String>,
         output: Value,
         diagnostics_output: Option<String>,
         diagnostics: Vec<WasmDiagnostic>,
@@ -140,14 +223,15 @@ impl WasmCompileResult {
             diagnostics_output,
             diagnostics,
             result: Some(format!("{:?}", output)),
-            output: io.drain_utf8().ok(),
+            output: captured,
             instructions,
+            replay: None,
         }
     }
 
     /// Construct a result from an error.
     fn from_error<E>(
-        io: &CaptureIo,
+        captured: Option<String>,
         error: E,
         diagnostics_output: Option<String>,
         diagnostics: Vec<WasmDiagnostic>,
@@ -161,142 +245,328 @@ impl WasmCompileResult {
             diagnostics_output,
             diagnostics,
             result: None,
-            output: io.drain_utf8().ok(),
+            output: captured,
             instructions,
+            replay: None,
         }
     }
+
+    /// Redact the secrets resolved by `session` from everything returned.
+    fn redacted(self, session: &Session) -> Self {
+        let redact = |text: Option<String>| text.map(|text| session.redact(&text));
+
+        Self {
+            error: redact(self.error),
+            diagnostics_output: redact(self.diagnostics_output),
+            diagnostics: self
+                .diagnostics
+                .into_iter()
+                .map(|d| WasmDiagnostic {
+                    message: session.redact(&d.message),
+                    ..d
+                })
+                .collect(),
+            result: redact(self.result),
+            output: redact(self.output),
+            replay: self.replay.map(|replay| replay.redacted(session)),
+            ..self
+        }
+    }
+
+    /// Attach the replay bundle recorded during execution.
+    fn with_replay(self, replay: Option<ReplayBundle>) -> Self {
+        Self { replay, ..self }
+    }
 }
 
 /// Setup a wasm-compatible context.
-fn setup_context(experimental: bool, io: &CaptureIo, params: SerdeValue, read_only: bool) -> Result<Context, ContextError> {
+///
+/// In sealed runs (deterministic, recording and replaying) the `http` module
+/// is left out and `rand` is replaced by a seeded implementation, so that
+/// everything the script depends on can be reproduced.
+fn setup_context(
+    experimental: bool,
+    io: &CaptureIo,
+    params: &RunContext,
+    read_only: bool,
+    sealed: bool,
+) -> Result<Context, ContextError> {
     let mut context = Context::with_config(false)?;
 
     context.install(rune::modules::capture_io::module(io)?)?;
     context.install(cyb::module(params, read_only)?)?;
-    context.install(rune_modules::http::module(true)?)?;
+    context.install(graph::module()?)?;
+    context.install(storage::module(read_only)?)?;
+    context.install(llm::module()?)?;
+
+    if !sealed {
+        context.install(rune_modules::http::module(true)?)?;
+    }
+
     context.install(rune_modules::json::module(true)?)?;
     context.install(rune_modules::toml::module(false)?)?;
-    context.install(rune_modules::rand::module(false)?)?;
+
+    if sealed {
+        context.install(deterministic::rand_module()?)?;
+    } else {
+        context.install(rune_modules::rand::module(false)?)?;
+    }
 
     if experimental {
         context.install(rune_modules::experiments::module(false)?)?;
     }
 
+    for module in extensions::modules(read_only)? {
+        context.install(module)?;
+    }
+
     Ok(context)
 }
 
+/// The sources of a run along with everything compiling them depends on.
+struct Build {
+    sources: rune::Sources,
+    options: Options,
+    profile: Rc<profile::Profile>,
+    /// Cache key of the sources compiled against the run context.
+    key: cache::Key,
+}
+
+/// The outcome of compiling a [Build].
+struct Compiled {
+    result: Result<Arc<rune::Unit>, rune::BuildError>,
+    docs: Arc<Docs>,
+    diagnostics: Vec<WasmDiagnostic>,
+    rendered: Vec<u8>,
+}
+
+impl Build {
+    fn new(
+        input: String,
+        scripts: String,
+        config: &Config,
+        read_only: bool,
+        sealed: bool,
+        params: &RunContext,
+    ) -> Result<Self, anyhow::Error> {
+        let key = cache::key(
+            &[input.as_str(), scripts.as_str()],
+            &config.options,
+            config.experimental,
+            read_only,
+            sealed,
+            params,
+        );
+
+        let mut sources = rune::Sources::new();
+
+        sources.insert(rune::Source::new("entry", input));
+
+        if scripts.len() > 0 {
+            sources.insert(rune::Source::new("entry", scripts));
+        }
+
+        let mut options = Options::default();
+
+        for option in &config.options {
+            options.parse_option(option)?;
+        }
+
+        Ok(Self {
+            sources,
+            options,
+            profile: profile::get(config.experimental, read_only, sealed)?,
+            key,
+        })
+    }
+
+    /// Compile the sources with the `cyb::context` constants set from
+    /// `params`, caching the unit if it compiles.
+    fn compile(&mut self, params: &RunContext) -> Result<Compiled, anyhow::Error> {
+        let context = self.profile.context(params)?;
+        let mut d = rune::Diagnostics::new();
+        let mut visitor = DocVisitor::default();
+
+        let result = rune::prepare(&mut self.sources)
+            .with_context(&context)
+            .with_diagnostics(&mut d)
+            .with_options(&self.options)
+            .with_visitor(&mut visitor)
+            .build()
+            .map(Arc::new);
+
+        let docs = Arc::new(visitor.docs);
+        let diagnostics = wasm_diagnostics(&d, &self.sources);
+        let mut rendered = rune::termcolor::Buffer::no_color();
+
+        d.emit(&mut rendered, &self.sources)
+            .context("emitting to buffer should never fail")?;
+
+        let rendered = rendered.into_inner();
+
+        if let Ok(unit) = &result {
+            cache::insert(self.key, CachedUnit {
+                unit: unit.clone(),
+                docs: docs.clone(),
+                diagnostics: diagnostics.clone(),
+                rendered: rendered.clone(),
+            });
+        }
+
+        Ok(Compiled {
+            result,
+            docs,
+            diagnostics,
+            rendered,
+        })
+    }
+}
+
 async fn inner_compile(
     input: String,
-    io: &CaptureIo,
     scripts: String,
-    params: JsValue,
-    compiler_params: JsValue
+    params: SerdeValue,
+    mut compiler_params: CompilerParams,
+    host: Rc<dyn CybHost>,
+    precompiled: Option<(&[u8], &[u8])>,
 ) -> Result<WasmCompileResult, anyhow::Error> {
-    // console_log!("compile: {:?}", JSON::stringify(&compiler_params));
-
-    let compiler_params: CompilerParams = JsValueSerdeExt::into_serde(&compiler_params)?;
     let instructions = None;
-    let config = compiler_params.config;
-    let params: SerdeValue = JsValueSerdeExt::into_serde(&params)?;
+    let mut config = compiler_params.config;
     let budget = config.budget.unwrap_or(1_000_000);
-    let mut sources = rune::Sources::new();
+    let sealed = config.sealed();
 
-    sources.insert(rune::Source::new("entry", input));
+    let (raw_params, seed, replay) = match config.replay.take() {
+        Some(bundle) if bundle.version != REPLAY_VERSION => {
+            anyhow::bail!("unsupported replay bundle version {}", bundle.version)
+        }
+        Some(bundle) => {
+            compiler_params.func_name = bundle.func_name;
+            compiler_params.func_params = bundle.func_params;
+            (bundle.params, bundle.seed, Some((bundle.source_hash, bundle.calls)))
+        }
+        None => {
+            // NB: a recording run draws a fresh seed, which is recorded so
+            // the replay draws the same numbers.
+            let seed = config.deterministic.as_ref().map_or_else(|| host.now() as u64, |d| d.seed);
+            (params, seed, None)
+        }
+    };
 
-    if scripts.len() > 0 {
-        sources.insert(rune::Source::new("entry", scripts));
-    }
+    let (params, warnings) = RunContext::parse(&raw_params)?;
+    let mut build = Build::new(input, scripts, &config, compiler_params.read_only, sealed, &params)?;
+    let profile = build.profile.clone();
+    let source_hash = build.key.iter().map(|b| format!("{:02x}", b)).collect::<String>();
 
-    let context = setup_context(config.experimental, io, params, compiler_params.read_only)?;
+    let replay = match replay {
+        Some((recorded, _)) if recorded != source_hash => {
+            anyhow::bail!("the replay bundle was recorded from other sources or settings")
+        }
+        Some((_, calls)) => Some(calls),
+        None => None,
+    };
 
-    let mut options = Options::default();
+    let environment = eval::Environment {
+        params: params.clone(),
+        experimental: config.experimental,
+        sealed,
+        options: config.options.clone(),
+        max_depth: config.max_eval_depth.unwrap_or(eval::MAX_DEPTH),
+    };
 
-    for option in &config.options {
-        options.parse_option(option)?;
-    }
+    // NB: a top-level script identified by its CID is on the call stack
+    // under it, so evaluating itself is caught right away.
+    let root = Frame {
+        cid: compiler_params.script_id.clone().filter(|id| cid::is_cid(id)),
+        scope: compiler_params.script_id.clone(),
+        func_name: compiler_params.func_name.clone(),
+        read_only: compiler_params.read_only,
+        tool: false,
+        program: None,
+    };
 
-    let mut d = rune::Diagnostics::new();
-    let mut diagnostics = Vec::new();
-    let result = rune::prepare(&mut sources)
-        .with_context(&context)
-        .with_diagnostics(&mut d)
-        .with_options(&options)
-        .build();
-    for diagnostic in d.diagnostics() {
-        match diagnostic {
-            Diagnostic::Fatal(error) => {
-                if let Some(source) = sources.get(error.source_id()) {
-                    match error.kind() {
-                        FatalDiagnosticKind::CompileError(error) => {
-                            let span = error.span();
+    let session = Rc::new(Session::new(
+        host,
+        environment,
+        root,
+        config.deterministic,
+        seed,
+        replay,
+        config.record,
+    ));
 
-                            let start = WasmPosition::from(
-                                source.pos_to_utf8_linecol(span.start.into_usize()),
-                            );
-                            let end = WasmPosition::from(
-                                source.pos_to_utf8_linecol(span.end.into_usize()),
-                            );
+    session.use_profile(&profile);
 
-                            diagnostics.push(WasmDiagnostic {
-                                kind: WasmDiagnosticKind::Error,
-                                start,
-                                end,
-                                message: error.to_string(),
-                            });
-                        }
-                        FatalDiagnosticKind::LinkError(error) => match error {
-                            LinkerError::MissingFunction { hash, spans } => {
-                                for (span, _) in spans {
-                                    let start = WasmPosition::from(
-                                        source.pos_to_utf8_linecol(span.start.into_usize()),
-                                    );
-                                    let end = WasmPosition::from(
-                                        source.pos_to_utf8_linecol(span.end.into_usize()),
-                                    );
+    let mut writer = rune::termcolor::Buffer::no_color();
 
-                                    diagnostics.push(WasmDiagnostic {
-                                        kind: WasmDiagnosticKind::Error,
-                                        start,
-                                        end,
-                                        message: format!("missing function (hash: {})", hash),
-                                    });
-                                }
-                            }
-                            _ => {}
-                        },
-                        _ => {}
-                    }
+    let key = build.key;
+
+    let cached = match (cache::get(&key), precompiled) {
+        (Some(cached), _) => Some(cached),
+        (None, Some((precompiled, signing_key))) => match bytecode::load(precompiled, &key, signing_key) {
+            Ok((unit, docs)) => {
+                let cached = CachedUnit {
+                    unit: Arc::new(unit),
+                    docs: Arc::new(docs),
+                    diagnostics: Vec::new(),
+                    rendered: Vec::new(),
+                };
+
+                cache::insert(key, cached.clone());
+                Some(cached)
+            }
+            Err(error) => {
+                if !config.suppress_text_warnings {
+                    writeln!(writer, "warning: precompiled unit ignored: {}", error)
+                        .context("writing to buffer should never fail")?;
                 }
+
+                None
             }
-            Diagnostic::Warning(warning) => {
-                let span = warning.span();
+        },
+        (None, None) => None,
+    };
 
-                if let Some(source) = sources.get(warning.source_id()) {
-                    let start =
-                        WasmPosition::from(source.pos_to_utf8_linecol(span.start.into_usize()));
-                    let end = WasmPosition::from(source.pos_to_utf8_linecol(span.end.into_usize()));
+    let (result, docs, mut diagnostics) = match cached {
+        Some(cached) => {
+            if !config.suppress_text_warnings {
+                writer
+                    .write_all(&cached.rendered)
+                    .context("writing to buffer should never fail")?;
+            }
 
-                    diagnostics.push(WasmDiagnostic {
-                        kind: WasmDiagnosticKind::Warning,
-                        start,
-                        end,
-                        message: warning.to_string(),
-                    });
-                }
+            (Ok(cached.unit), cached.docs, cached.diagnostics)
+        }
+        None => {
+            let compiled = build.compile(&params)?;
+
+            if !config.suppress_text_warnings {
+                writer
+                    .write_all(&compiled.rendered)
+                    .context("writing to buffer should never fail")?;
             }
-            _ => {}
+
+            (compiled.result, compiled.docs, compiled.diagnostics)
         }
-    }
-    let mut writer = rune::termcolor::Buffer::no_color();
+    };
 
-    if !config.suppress_text_warnings {
-        d.emit(&mut writer, &sources)
-            .context("emitting to buffer should never fail")?;
+    // NB: params aren't part of the sources, so their warnings have no
+    // position.
+    for warning in warnings {
+        if !config.suppress_text_warnings {
+            writeln!(writer, "warning: {}", warning).context("writing to buffer should never fail")?;
+        }
+
+        diagnostics.push(WasmDiagnostic {
+            kind: WasmDiagnosticKind::Warning,
+            start: WasmPosition::default(),
+            end: WasmPosition::default(),
+            message: warning,
+        });
     }
 
     if !compiler_params.execute {
-        return Ok(WasmCompileResult::output(
-            io,
+        return Ok(WasmCompileResult::from_output(
+            session.take_output(),
             Value::from(String::from("")),
             diagnostics_output(writer),
             diagnostics,
@@ -305,10 +575,10 @@ async fn inner_compile(
     }
 
     let unit = match result {
-        Ok(unit) => Arc::new(unit),
+        Ok(unit) => unit,
         Err(error) => {
             return Ok(WasmCompileResult::from_error(
-                io,
+                session.take_output(),
                 error,
                 diagnostics_output(writer),
                 diagnostics,
@@ -318,14 +588,20 @@ async fn inner_compile(
     };
     let instructions = if config.instructions {
         let mut out = rune::termcolor::Buffer::no_color();
-        unit.emit_instructions(&mut out, &sources, false)
+        unit.emit_instructions(&mut out, &build.sources, false)
             .expect("dumping to string shouldn't fail");
         Some(diagnostics_output(out).context("converting instructions to UTF-8")?)
     } else {
         None
     };
 
-    let mut vm = rune::Vm::new(Arc::new(context.runtime()), unit);
+    session.set_program(Program {
+        unit: unit.clone(),
+        runtime: profile.runtime(),
+        docs,
+    });
+
+    let mut vm = rune::Vm::new(profile.runtime(), unit);
 
     // let mut params:  Vec<Value> = Vec::new();
 
@@ -335,24 +611,38 @@ async fn inner_compile(
 
     let params_vec = map_params_to_vec(&compiler_params.func_params);
 
+    let replay_bundle = || {
+        session.take_recording().map(|calls| ReplayBundle {
+            version: REPLAY_VERSION,
+            source_hash: source_hash.clone(),
+            params: raw_params.clone(),
+            seed,
+            func_name: compiler_params.func_name.clone(),
+            func_params: compiler_params.func_params.clone(),
+            calls,
+        })
+    };
+
     let mut execution = match vm.execute([&compiler_params.func_name], params_vec) {
         Ok(execution) => execution,
         Err(error) => {
             error
-                .emit(&mut writer, &sources)
+                .emit(&mut writer, &build.sources)
                 .context("emitting to buffer should never fail")?;
 
             return Ok(WasmCompileResult::from_error(
-                io,
+                session.take_output(),
                 error,
                 diagnostics_output(writer),
                 diagnostics,
                 instructions,
-            ));
+            )
+            .with_replay(replay_bundle())
+            .redacted(&session));
         }
     };
 
-    let future = budget::with(budget, execution.async_complete());
+    let future = session::with(session.clone(), budget::with(budget, execution.async_complete()));
 
     let output = match future.await {
         VmResult::Ok(output) => output,
@@ -367,7 +657,7 @@ async fn inner_compile(
             // NB: emit diagnostics if debug info is available.
             if let Some(debug) = unit.debug_info() {
                 if let Some(inst) = debug.instruction_at(ip) {
-                    if let Some(source) = sources.get(inst.source_id) {
+                    if let Some(source) = build.sources.get(inst.source_id) {
                         let start = WasmPosition::from(
                             source.pos_to_utf8_linecol(inst.span.start.into_usize()),
                         );
@@ -386,26 +676,103 @@ async fn inner_compile(
             }
 
             error
-                .emit(&mut writer, &sources)
+                .emit(&mut writer, &build.sources)
                 .context("emitting to buffer should never fail")?;
 
             return Ok(WasmCompileResult::from_error(
-                io,
+                session.take_output(),
                 error,
                 diagnostics_output(writer),
                 diagnostics,
                 instructions,
-            ));
+            )
+            .with_replay(replay_bundle())
+            .redacted(&session));
         }
     };
 
-    Ok(WasmCompileResult::output(
-        io,
+    Ok(WasmCompileResult::from_output(
+        session.take_output(),
         output,
         diagnostics_output(writer),
         diagnostics,
         instructions,
-    ))
+    )
+    .with_replay(replay_bundle())
+    .redacted(&session))
+}
+
+/// Convert compile diagnostics into positioned diagnostics for the editor.
+fn wasm_diagnostics(d: &rune::Diagnostics, sources: &rune::Sources) -> Vec<WasmDiagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for diagnostic in d.diagnostics() {
+        match diagnostic {
+            Diagnostic::Fatal(error) => {
+                if let Some(source) = sources.get(error.source_id()) {
+                    match error.kind() {
+                        FatalDiagnosticKind::CompileError(error) => {
+                            let span = error.span();
+
+                            let start = WasmPosition::from(
+                                source.pos_to_utf8_linecol(span.start.into_usize()),
+                            );
+                            let end = WasmPosition::from(
+                                source.pos_to_utf8_linecol(span.end.into_usize()),
+                            );
+
+                            diagnostics.push(WasmDiagnostic {
+                                kind: WasmDiagnosticKind::Error,
+                                start,
+                                end,
+                                message: error.to_string(),
+                            });
+                        }
+                        FatalDiagnosticKind::LinkError(error) => match error {
+                            LinkerError::MissingFunction { hash, spans } => {
+                                for (span, _) in spans {
+                                    let start = WasmPosition::from(
+                                        source.pos_to_utf8_linecol(span.start.into_usize()),
+                                    );
+                                    let end = WasmPosition::from(
+                                        source.pos_to_utf8_linecol(span.end.into_usize()),
+                                    );
+
+                                    diagnostics.push(WasmDiagnostic {
+                                        kind: WasmDiagnosticKind::Error,
+                                        start,
+                                        end,
+                                        message: format!("missing function (hash: {})", hash),
+                                    });
+                                }
+                            }
+                            _ => {}
+                        },
+                        _ => {}
+                    }
+                }
+            }
+            Diagnostic::Warning(warning) => {
+                let span = warning.span();
+
+                if let Some(source) = sources.get(warning.source_id()) {
+                    let start =
+                        WasmPosition::from(source.pos_to_utf8_linecol(span.start.into_usize()));
+                    let end = WasmPosition::from(source.pos_to_utf8_linecol(span.end.into_usize()));
+
+                    diagnostics.push(WasmDiagnostic {
+                        kind: WasmDiagnosticKind::Warning,
+                        start,
+                        end,
+                        message: warning.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    diagnostics
 }
 
 fn diagnostics_output(writer: rune::termcolor::Buffer) -> Option<String> {
@@ -416,18 +783,201 @@ fn diagnostics_output(writer: rune::termcolor::Buffer) -> Option<String> {
 }
 
 
+/// Compile and run a script against the `cyb` module served by `host`.
+///
+/// This is what [compile] does with the JS bindings as host, and can be used
+/// natively with e.g. a [host::MemoryHost].
+pub async fn run(
+    input: String,
+    scripts: String,
+    params: SerdeValue,
+    compiler_params: CompilerParams,
+    host: Rc<dyn CybHost>,
+) -> WasmCompileResult {
+    match inner_compile(input, scripts, params, compiler_params, host, None).await {
+        Ok(result) => result,
+        Err(error) => WasmCompileResult::from_error(None, error, None, Vec::new(), None),
+    }
+}
+
+/// Like [run], but loading the unit from `precompiled` bytes produced by
+/// [precompile_unit] with the same `signing_key` instead of compiling.
+///
+/// The script is compiled from source if the bytes were produced by another
+/// version, from other sources or settings, or weren't signed with
+/// `signing_key`.
+pub async fn run_precompiled(
+    input: String,
+    scripts: String,
+    precompiled: &[u8],
+    signing_key: &[u8],
+    params: SerdeValue,
+    compiler_params: CompilerParams,
+    host: Rc<dyn CybHost>,
+) -> WasmCompileResult {
+    let precompiled = Some((precompiled, signing_key));
+
+    match inner_compile(input, scripts, params, compiler_params, host, precompiled).await {
+        Ok(result) => result,
+        Err(error) => WasmCompileResult::from_error(None, error, None, Vec::new(), None),
+    }
+}
+
+/// Compile a script into bytes which [run_precompiled] can load without
+/// compiling, signed with `signing_key`.
+///
+/// The run context is compiled into the unit, so the run has to use the same
+/// sources, params and compiler params for the bytes to be used.
+pub fn precompile_unit(
+    input: String,
+    scripts: String,
+    params: SerdeValue,
+    signing_key: &[u8],
+    compiler_params: CompilerParams,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let config = compiler_params.config;
+    let sealed = config.sealed();
+    let (params, _) = RunContext::parse(&params)?;
+    let mut build = Build::new(input, scripts, &config, compiler_params.read_only, sealed, &params)?;
+
+    let (unit, docs) = match cache::get(&build.key) {
+        Some(cached) => (cached.unit, cached.docs),
+        None => {
+            let compiled = build.compile(&params)?;
+
+            match compiled.result {
+                Ok(unit) => (unit, compiled.docs),
+                Err(error) => {
+                    let rendered = String::from_utf8_lossy(&compiled.rendered);
+                    anyhow::bail!("{}\n{}", error, rendered.trim_end())
+                }
+            }
+        }
+    };
+
+    bytecode::save(build.key, &unit, &docs, signing_key)
+}
+
+/// Provide the object implementing the host bindings (`jsCyberSearch`,
+/// `jsGetIpfsTextContent`, ...). Must be called before [compile].
+#[wasm_bindgen]
+pub fn init_bindings(bindings: JsValue) -> Result<(), JsValue> {
+    let host = WasmHost::new(bindings).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    host.install();
+    Ok(())
+}
+
+/// Register an async host function implemented in JS.
+///
+/// The descriptor is `{ module, name, arity, capability, doc }`, where
+/// `capability` is `"read"` or `"write"` and `doc` is an optional doc string
+/// shown to script authors. Write functions are left out of read-only runs.
+/// The function becomes available as `module::name` to scripts compiled
+/// afterwards.
+#[wasm_bindgen]
+pub fn register_function(descriptor: JsValue, handler: js_sys::Function) -> Result<(), JsValue> {
+    let descriptor = JsValueSerdeExt::into_serde(&descriptor)
+        .map_err(|error| JsValue::from_str(&error.to_string()))?;
+
+    extensions::register(descriptor, handler).map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Hits, misses and evictions of the compiled unit cache, along with its
+/// current size and capacity.
+#[wasm_bindgen]
+pub fn unit_cache_stats() -> JsValue {
+    <JsValue as JsValueSerdeExt>::from_serde(&cache_stats()).unwrap()
+}
+
+/// The stats returned by [unit_cache_stats], for native use.
+///
+/// The cache is per thread, so these are the stats of the current thread.
+pub fn cache_stats() -> CacheStats {
+    cache::stats()
+}
+
+/// Drop every cached compiled unit. The stats are kept.
+#[wasm_bindgen]
+pub fn clear_unit_cache() {
+    cache::clear();
+}
+
+/// Descriptors of every function registered with [register_function],
+/// including their doc strings.
+#[wasm_bindgen]
+pub fn registered_functions() -> JsValue {
+    <JsValue as JsValueSerdeExt>::from_serde(&extensions::descriptors()).unwrap()
+}
+
 #[wasm_bindgen]
 pub async fn compile(input: String, scripts: String, params: JsValue, compiler_params: JsValue) -> JsValue {
-    let io = CaptureIo::new();
+    let result = match prepare_run(&params, &compiler_params) {
+        Ok((params, compiler_params, host)) => {
+            run(input, scripts, params, compiler_params, host).await
+        }
+        Err(error) => WasmCompileResult::from_error(None, error, None, Vec::new(), None),
+    };
 
-    let result = match inner_compile(input, &io, scripts, params, compiler_params).await {
-        Ok(result) => result,
-        Err(error) => WasmCompileResult::from_error(&io, error, None, Vec::new(), None),
+    <JsValue as JsValueSerdeExt>::from_serde(&result).unwrap()
+}
+
+/// Compile a script into bytes which [compile_precompiled] can run without
+/// compiling, e.g. to store them next to the script. The bytes are signed
+/// with `signing_key`, a secret of at least 16 bytes kept by the app, and
+/// only serve runs with the same `params`.
+#[wasm_bindgen]
+pub fn precompile(
+    input: String,
+    scripts: String,
+    params: JsValue,
+    signing_key: Vec<u8>,
+    compiler_params: JsValue,
+) -> Result<Vec<u8>, JsValue> {
+    let parsed = (
+        JsValueSerdeExt::into_serde(&params),
+        JsValueSerdeExt::into_serde(&compiler_params),
+    );
+
+    let precompiled = match parsed {
+        (Ok(params), Ok(compiler_params)) => precompile_unit(input, scripts, params, &signing_key, compiler_params),
+        (Err(error), _) | (_, Err(error)) => Err(error.into()),
+    };
+
+    precompiled.map_err(|error| JsValue::from_str(&error.to_string()))
+}
+
+/// Like [compile], but loading the unit from bytes produced by [precompile]
+/// with the same `signing_key`. Falls back to compiling if they don't match
+/// the script, settings or key.
+#[wasm_bindgen]
+pub async fn compile_precompiled(
+    input: String,
+    scripts: String,
+    precompiled: Vec<u8>,
+    signing_key: Vec<u8>,
+    params: JsValue,
+    compiler_params: JsValue,
+) -> JsValue {
+    let result = match prepare_run(&params, &compiler_params) {
+        Ok((params, compiler_params, host)) => {
+            run_precompiled(input, scripts, &precompiled, &signing_key, params, compiler_params, host).await
+        }
+        Err(error) => WasmCompileResult::from_error(None, error, None, Vec::new(), None),
     };
 
     <JsValue as JsValueSerdeExt>::from_serde(&result).unwrap()
 }
 
+fn prepare_run(
+    params: &JsValue,
+    compiler_params: &JsValue,
+) -> Result<(SerdeValue, CompilerParams, Rc<dyn CybHost>), anyhow::Error> {
+    let host: Rc<dyn CybHost> = WasmHost::installed()?;
+    let params = JsValueSerdeExt::into_serde(params)?;
+    let compiler_params = JsValueSerdeExt::into_serde(compiler_params)?;
+    Ok((params, compiler_params, host))
+}
+
 
 // pub async fn execute() -> JsValue {
 //     let context = rune_modules::default_context()?;
@@ -0,0 +1,140 @@
+//! The `cyb::llm` module: chatting with an LLM.
+//!
+//! The provider (OpenAI, a local model, a mock) is chosen by the host, scripts
+//! only pick among its models through the options.
+//!
+//! Streamed answers are recorded chunk by chunk, as `llm_stream_next` calls
+//! numbered by stream and chunk, so they replay like any other host call.
+
+use anyhow::anyhow;
+use rune::runtime::{Mut, Object, Vec, VmResult};
+use rune::{vm_try, Any, ContextError, Module};
+use serde_json::{json, Value as SerdeValue};
+
+use crate::agent;
+use crate::helpers::{from_json, from_options};
+use crate::host::{ready, ChatMessage, ChatOptions, ChatRole, HostStream};
+use crate::session;
+use crate::types::ChatResponse;
+
+/// An answer being streamed, returned by `cyb::llm::stream`.
+#[derive(Any)]
+pub(crate) struct ChatStream {
+    /// Number of the stream within the run.
+    id: usize,
+    /// Number of chunks read so far.
+    index: usize,
+    /// The stream answered by the host, `None` when replaying.
+    live: Option<Box<dyn HostStream>>,
+    done: bool,
+}
+
+/// Decode and check the arguments of the function `name`.
+pub(crate) fn request(name: &str, messages: &Vec, options: &Object) -> VmResult<(std::vec::Vec<ChatMessage>, ChatOptions)> {
+    let messages: std::vec::Vec<ChatMessage> = vm_try!(from_options(name, messages));
+    let options: ChatOptions = vm_try!(from_options(name, options));
+
+    if messages.is_empty() {
+        return VmResult::panic(format!("{}: at least one message is required", name));
+    }
+
+    if messages.iter().any(|message| message.role == ChatRole::Tool) {
+        return VmResult::panic(format!("{}: `role` must be `system`, `user` or `assistant`", name));
+    }
+
+    if let Some(temperature) = options.temperature {
+        if !(0.0..=2.0).contains(&temperature) {
+            return VmResult::panic(format!("{}: temperature {} is not between 0 and 2", name, temperature));
+        }
+    }
+
+    if options.max_tokens == Some(0) {
+        return VmResult::panic(format!("{}: max_tokens must be positive", name));
+    }
+
+    VmResult::Ok((messages, options))
+}
+
+/// Continue the chat in `messages`, a list of `#{ role, content }` objects
+/// with `role` being `system`, `user` or `assistant`, with `options` being
+/// `#{ model, temperature, max_tokens }`.
+async fn chat(messages: Vec, options: Object) -> VmResult<ChatResponse> {
+    let (messages, options) = vm_try!(request("llm::chat", &messages, &options));
+
+    let session = vm_try!(session::current());
+    let args = json!([messages, options]);
+    let result = vm_try!(session.call_json("llm_chat", args, |host| host.llm_chat(&messages, &options)).await);
+
+    match result {
+        SerdeValue::Null => VmResult::panic("llm::chat: the host returned no response"),
+        SerdeValue::String(content) => VmResult::Ok(ChatResponse {
+            content,
+            ..ChatResponse::default()
+        }),
+        SerdeValue::Object(ref response) if !response.contains_key("content") => {
+            VmResult::panic("llm::chat: the host response has no `content`")
+        }
+        result => from_json("llm::chat", result),
+    }
+}
+
+/// Like `chat`, but streaming the answer: `next` resolves to each chunk as
+/// it arrives, then to `None`.
+async fn stream(messages: Vec, options: Object) -> VmResult<ChatStream> {
+    let (messages, options) = vm_try!(request("llm::stream", &messages, &options));
+
+    let session = vm_try!(session::current());
+    let args = json!([messages, options]);
+    let mut live = None;
+
+    vm_try!(session.call_json("llm_stream", args, |host| {
+        live = Some(host.llm_stream(&messages, &options));
+        ready(Ok(SerdeValue::Null))
+    }).await);
+
+    VmResult::Ok(ChatStream {
+        id: session.next_stream_id(),
+        index: 0,
+        live,
+        done: false,
+    })
+}
+
+/// The next chunk of `stream`, `None` once the answer is complete.
+async fn next(mut stream: Mut<ChatStream>) -> VmResult<Option<String>> {
+    if stream.done {
+        return VmResult::Ok(None);
+    }
+
+    let session = vm_try!(session::current());
+    let args = json!([stream.id, stream.index]);
+    let live = &mut stream.live;
+
+    let result = vm_try!(session.call_json("llm_stream_next", args, |_| match live {
+        Some(live) => live.next(),
+        None => ready(Err(anyhow!("stream is not open"))),
+    }).await);
+
+    stream.index += 1;
+
+    match result {
+        SerdeValue::Null => {
+            stream.done = true;
+            VmResult::Ok(None)
+        }
+        SerdeValue::String(chunk) => VmResult::Ok(Some(chunk)),
+        result => VmResult::panic(format!("llm::stream: unexpected host response: {}", result)),
+    }
+}
+
+/// The `cyb::llm` module.
+pub fn module() -> Result<Module, ContextError> {
+    let mut module = Module::with_crate_item("cyb", ["llm"]);
+    module.ty::<ChatStream>()?;
+    module.function(["chat"], chat)?;
+    module.function(["stream"], stream)?;
+    module.associated_function("next", next)?;
+    module.function(["agent"], agent::agent)?;
+    module.function(["agent_with"], agent::agent_with)?;
+    Ok(module)
+}
@@ -0,0 +1,118 @@
+//! Runtime contexts shared between runs.
+//!
+//! Installing every module and building a runtime context is a large share
+//! of a short run, yet it only depends on a handful of settings. Runtime
+//! contexts are therefore built once per permission profile and shared.
+//!
+//! The per-run parts are kept out of them: the run context is read from the
+//! session, and output is written to a buffer of the profile, which sessions
+//! drain into their own output after every poll. The context to compile
+//! against is kept as well, rebuilt only when the run context compiled into
+//! the `cyb::context` constants changes.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use rune::modules::capture_io::CaptureIo;
+use rune::runtime::RuntimeContext;
+use rune::{Context, ContextError};
+
+use crate::context::RunContext;
+use crate::extensions;
+
+thread_local! {
+    static PROFILES: RefCell<Vec<Rc<Profile>>> = RefCell::new(Vec::new());
+}
+
+/// The settings a context is built from, besides per-run data.
+#[derive(Clone, PartialEq, Eq)]
+struct Settings {
+    experimental: bool,
+    read_only: bool,
+    /// Whether runs are sealed, see [crate::setup_context].
+    sealed: bool,
+    /// Registered functions, as JSON.
+    extensions: String,
+}
+
+/// A runtime context shared by every run with the same settings.
+pub(crate) struct Profile {
+    settings: Settings,
+    runtime: Arc<RuntimeContext>,
+    io: CaptureIo,
+    /// The context last compiled against, with the run context it was built
+    /// with as JSON.
+    compile: RefCell<Option<(String, Rc<Context>)>>,
+}
+
+impl Profile {
+    /// The runtime context to run units compiled by [Profile::context] with.
+    pub(crate) fn runtime(&self) -> Arc<RuntimeContext> {
+        self.runtime.clone()
+    }
+
+    /// The context to compile against, with the `cyb::context` constants set
+    /// from `params`.
+    pub(crate) fn context(&self, params: &RunContext) -> Result<Rc<Context>, ContextError> {
+        let json = serde_json::to_string(params).unwrap_or_default();
+
+        if let Some((built, context)) = &*self.compile.borrow() {
+            if *built == json {
+                return Ok(context.clone());
+            }
+        }
+
+        let context = Rc::new(crate::setup_context(
+            self.settings.experimental,
+            &self.io,
+            params,
+            self.settings.read_only,
+            self.settings.sealed,
+        )?);
+
+        *self.compile.borrow_mut() = Some((json, context.clone()));
+        Ok(context)
+    }
+
+    /// Take the output written by scripts since the last call.
+    pub(crate) fn drain_output(&self) -> Vec<u8> {
+        self.io.drain()
+    }
+}
+
+/// The profile for the given settings, built on first use.
+///
+/// Profiles built before functions were registered or replaced are dropped.
+pub(crate) fn get(experimental: bool, read_only: bool, sealed: bool) -> Result<Rc<Profile>, ContextError> {
+    let settings = Settings {
+        experimental,
+        read_only,
+        sealed,
+        extensions: serde_json::to_string(&extensions::descriptors()).unwrap_or_default(),
+    };
+
+    let existing = PROFILES.with(|profiles| {
+        let mut profiles = profiles.borrow_mut();
+        profiles.retain(|p| p.settings.extensions == settings.extensions);
+        profiles.iter().find(|p| p.settings == settings).cloned()
+    });
+
+    if let Some(profile) = existing {
+        return Ok(profile);
+    }
+
+    let io = CaptureIo::new();
+    let params = RunContext::default();
+    let context = crate::setup_context(experimental, &io, &params, read_only, sealed)?;
+
+    let profile = Rc::new(Profile {
+        settings,
+        runtime: Arc::new(context.runtime()),
+        io,
+        compile: RefCell::new(Some((serde_json::to_string(&params).unwrap_or_default(), Rc::new(context)))),
+    });
+
+    PROFILES.with(|profiles| profiles.borrow_mut().push(profile.clone()));
+    Ok(profile)
+}
@@ -0,0 +1,566 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rune::runtime::{RuntimeContext, VmResult, Value as VmValue};
+use rune::{vm_try, Unit};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as SerdeValue};
+
+use crate::deterministic::{Deterministic, WyRand};
+use crate::docs::Docs;
+use crate::eval::Environment;
+use crate::helpers::map_to_rune_value;
+use crate::host::{CybHost, HostFuture};
+use crate::profile::Profile;
+use crate::types::Secret;
+
+/// Version of the replay bundle format.
+pub(crate) const REPLAY_VERSION: u32 = 2;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Session>>> = RefCell::new(None);
+}
+
+/// A host call together with the value the host answered with.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct HostCall {
+    /// Name of the host function, e.g. `cyber_search`.
+    pub(crate) call: String,
+    /// Arguments the function was called with.
+    #[serde(default)]
+    pub(crate) args: SerdeValue,
+    /// Value returned by the host.
+    #[serde(default)]
+    pub(crate) result: SerdeValue,
+}
+
+/// Everything a run depended on besides its sources, enough to reproduce it
+/// without the host.
+///
+/// Recording and replaying runs are sealed (see [crate::setup_context]): the
+/// seeded `rand` draws are reproduced from `seed`, and `http` isn't available
+/// since its calls couldn't be recorded.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReplayBundle {
+    /// Format version, see [REPLAY_VERSION].
+    pub(crate) version: u32,
+    /// Hex-encoded hash of the sources and settings of the run, which a
+    /// replay has to match.
+    #[serde(default)]
+    pub(crate) source_hash: String,
+    /// Run params, replayed in place of those passed.
+    #[serde(default)]
+    pub(crate) params: SerdeValue,
+    /// Seed of the `rand` module.
+    #[serde(default)]
+    pub(crate) seed: u64,
+    /// The function that was executed.
+    pub(crate) func_name: String,
+    /// Parameters the function was executed with.
+    #[serde(default)]
+    pub(crate) func_params: SerdeValue,
+    /// Host calls in the order they were made.
+    #[serde(default)]
+    pub(crate) calls: Vec<HostCall>,
+}
+
+impl ReplayBundle {
+    /// Redact the secrets resolved by `session` from the recorded values.
+    pub(crate) fn redacted(mut self, session: &Session) -> Self {
+        session.redact_json(&mut self.params);
+        session.redact_json(&mut self.func_params);
+
+        for call in &mut self.calls {
+            session.redact_json(&mut call.args);
+            session.redact_json(&mut call.result);
+        }
+
+        self
+    }
+}
+
+/// A compiled script along with what it runs against.
+#[derive(Clone)]
+pub(crate) struct Program {
+    pub(crate) unit: Arc<Unit>,
+    pub(crate) runtime: Arc<RuntimeContext>,
+    /// Doc comments of the unit's functions.
+    pub(crate) docs: Arc<Docs>,
+}
+
+/// A script on the call stack of a run.
+#[derive(Clone)]
+pub(crate) struct Frame {
+    /// CID of the script, `None` for a top-level script whose `scriptId`
+    /// isn't a CID.
+    pub(crate) cid: Option<String>,
+    /// Identity of the script scoping its storage, if it has one.
+    pub(crate) scope: Option<String>,
+    /// The function being run.
+    pub(crate) func_name: String,
+    /// Whether the script is read-only.
+    pub(crate) read_only: bool,
+    /// Whether the function was called as a tool by `llm::agent`, from the
+    /// script of the frame below.
+    pub(crate) tool: bool,
+    /// The script once compiled.
+    pub(crate) program: Option<Program>,
+}
+
+/// State of a single script run, available to the host functions while the
+/// script is being polled.
+pub(crate) struct Session {
+    host: Rc<dyn CybHost>,
+    environment: Environment,
+    frames: RefCell<Vec<Frame>>,
+    profiles: RefCell<Vec<Rc<Profile>>>,
+    output: RefCell<Vec<u8>>,
+    secrets: RefCell<Vec<String>>,
+    streams: Cell<usize>,
+    storage_keys: RefCell<HashMap<String, HashSet<String>>>,
+    deterministic: Option<Deterministic>,
+    rng: WyRand,
+    replay: Option<RefCell<VecDeque<HostCall>>>,
+    recording: Option<RefCell<Vec<HostCall>>>,
+}
+
+impl Session {
+    pub(crate) fn new(
+        host: Rc<dyn CybHost>,
+        environment: Environment,
+        root: Frame,
+        deterministic: Option<Deterministic>,
+        seed: u64,
+        replay: Option<Vec<HostCall>>,
+        record: bool,
+    ) -> Self {
+        Self {
+            host,
+            environment,
+            frames: RefCell::new(vec![root]),
+            profiles: RefCell::new(Vec::new()),
+            output: RefCell::new(Vec::new()),
+            secrets: RefCell::new(Vec::new()),
+            streams: Cell::new(0),
+            storage_keys: RefCell::new(HashMap::new()),
+            deterministic,
+            rng: WyRand::new(seed),
+            replay: replay.map(|calls| RefCell::new(calls.into())),
+            recording: record.then(|| RefCell::new(Vec::new())),
+        }
+    }
+
+    /// The host serving this run.
+    pub(crate) fn host(&self) -> &dyn CybHost {
+        &*self.host
+    }
+
+    /// How the run was set up.
+    pub(crate) fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Whether the script currently running is read-only.
+    pub(crate) fn read_only(&self) -> bool {
+        self.frames.borrow().last().map_or(false, |frame| frame.read_only)
+    }
+
+    /// Identity of the script currently running, scoping its storage.
+    pub(crate) fn scope(&self) -> Option<String> {
+        self.frames.borrow().last().and_then(|frame| frame.scope.clone())
+    }
+
+    /// The script currently running, once compiled.
+    pub(crate) fn program(&self) -> Option<Program> {
+        self.frames.borrow().last().and_then(|frame| frame.program.clone())
+    }
+
+    /// Set the compiled script currently running.
+    pub(crate) fn set_program(&self, program: Program) {
+        if let Some(frame) = self.frames.borrow_mut().last_mut() {
+            frame.program = Some(program);
+        }
+    }
+
+    /// The scripts that led to the one currently running, outermost first.
+    pub(crate) fn call_stack(&self) -> Vec<Frame> {
+        self.frames.borrow().clone()
+    }
+
+    /// The call stack of a nested script called from the one currently
+    /// running, failing if it is already on the stack or if the stack is too
+    /// deep. The nested script runs with it through [with_frames].
+    pub(crate) fn enter(&self, frame: Frame) -> VmResult<Vec<Frame>> {
+        let frames = self.frames.borrow();
+        let depth = frames.iter().filter(|f| !f.tool).count() - 1;
+
+        if frames.iter().any(|f| f.cid.is_some() && f.cid == frame.cid) {
+            let chain = frames
+                .iter()
+                .filter(|f| !f.tool)
+                .filter_map(|f| f.cid.as_deref())
+                .chain(frame.cid.as_deref())
+                .collect::<Vec<_>>();
+
+            return VmResult::panic(format!("script cycle detected: {}", chain.join(" -> ")));
+        }
+
+        if depth >= self.environment.max_depth {
+            return VmResult::panic(format!(
+                "nested evaluation is limited to a depth of {}",
+                self.environment.max_depth
+            ));
+        }
+
+        let mut frames = frames.clone();
+        frames.push(frame);
+        VmResult::Ok(frames)
+    }
+
+    /// The call stack of the function `func_name` of the script currently
+    /// running, called as a tool by `llm::agent`. It runs with it through
+    /// [with_frames].
+    pub(crate) fn enter_tool(&self, func_name: &str) -> Vec<Frame> {
+        let mut frames = self.frames.borrow().clone();
+
+        if let Some(frame) = frames.last().cloned() {
+            frames.push(Frame {
+                func_name: func_name.to_owned(),
+                tool: true,
+                ..frame
+            });
+        }
+
+        frames
+    }
+
+    /// Collect the output scripts of this run write through `profile`.
+    pub(crate) fn use_profile(&self, profile: &Rc<Profile>) {
+        let mut profiles = self.profiles.borrow_mut();
+
+        if !profiles.iter().any(|p| Rc::ptr_eq(p, profile)) {
+            profiles.push(profile.clone());
+        }
+    }
+
+    /// Move output written through the profiles in use into this run's
+    /// output, passing it on to the host as it arrives.
+    ///
+    /// Called after every poll: runs interleave only between polls, so
+    /// whatever was written during one belongs to this run.
+    pub(crate) fn collect_output(&self) {
+        let mut output = self.output.borrow_mut();
+
+        for profile in self.profiles.borrow().iter() {
+            let chunk = profile.drain_output();
+
+            if !chunk.is_empty() {
+                self.host.output(&self.redact(&String::from_utf8_lossy(&chunk)));
+                output.extend(chunk);
+            }
+        }
+    }
+
+    /// Take the output of this run so far.
+    pub(crate) fn take_output(&self) -> Option<String> {
+        self.collect_output();
+        String::from_utf8(self.output.take()).ok()
+    }
+
+    /// Whether host calls are answered by the host, rather than by a replay
+    /// bundle or a snapshot.
+    pub(crate) fn is_live(&self) -> bool {
+        self.replay.is_none() && self.deterministic.is_none()
+    }
+
+    /// Resolve the value of a secret through the host.
+    ///
+    /// This is not a recorded host call. The value is remembered so it can be
+    /// redacted from everything the run returns.
+    pub(crate) async fn secret(&self, secret: &Secret) -> VmResult<String> {
+        let value = match self.host.secret(&secret.name).await {
+            Ok(SerdeValue::String(value)) => value,
+            Ok(SerdeValue::Null) => return VmResult::panic(format!("secret `{}` is not available", secret.name)),
+            Ok(_) => return VmResult::panic(format!("secret `{}` is not a string", secret.name)),
+            Err(error) => return VmResult::panic(format!("resolving secret `{}` failed: {}", secret.name, error)),
+        };
+
+        self.add_secret(&value);
+        VmResult::Ok(value)
+    }
+
+    /// Redact `value` from everything the run returns.
+    pub(crate) fn add_secret(&self, value: &str) {
+        let mut secrets = self.secrets.borrow_mut();
+
+        if !value.is_empty() && !secrets.iter().any(|s| s == value) {
+            secrets.push(value.to_owned());
+        }
+    }
+
+    /// Replace the secret values resolved during this run in `text`.
+    pub(crate) fn redact(&self, text: &str) -> String {
+        let mut secrets = self.secrets.borrow().clone();
+
+        // NB: longest first, so a secret containing another one is fully
+        // redacted.
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+
+        secrets
+            .iter()
+            .fold(text.to_owned(), |text, secret| text.replace(secret.as_str(), "[redacted]"))
+    }
+
+    /// Replace the secret values resolved during this run in the strings and
+    /// keys of `value`.
+    pub(crate) fn redact_json(&self, value: &mut SerdeValue) {
+        match value {
+            SerdeValue::String(text) => *text = self.redact(text),
+            SerdeValue::Array(values) => values.iter_mut().for_each(|value| self.redact_json(value)),
+            SerdeValue::Object(object) => {
+                *object = std::mem::take(object)
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        self.redact_json(&mut value);
+                        (self.redact(&key), value)
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    /// Identify a new stream opened by this run, numbered in order so that
+    /// replays match.
+    pub(crate) fn next_stream_id(&self) -> usize {
+        let id = self.streams.get();
+        self.streams.set(id + 1);
+        id
+    }
+
+    /// Whether the keys stored in `scope` are known to the run.
+    pub(crate) fn tracks_storage(&self, scope: &str) -> bool {
+        self.storage_keys.borrow().contains_key(scope)
+    }
+
+    /// Start tracking the keys stored in `scope`, as listed by the host. Keys
+    /// tracked in the meantime are kept.
+    pub(crate) fn track_storage(&self, scope: &str, keys: Vec<String>) {
+        self.storage_keys
+            .borrow_mut()
+            .entry(scope.to_owned())
+            .or_insert_with(|| keys.into_iter().collect());
+    }
+
+    /// Reserve `key` in `scope` before it is written, failing if `scope`
+    /// already holds `max` keys. Returns whether the key is new, in which case
+    /// it has to be released if the write fails.
+    pub(crate) fn reserve_storage_key(&self, scope: &str, key: &str, max: usize) -> Result<bool, ()> {
+        let mut storage_keys = self.storage_keys.borrow_mut();
+        let keys = storage_keys.entry(scope.to_owned()).or_default();
+
+        if keys.contains(key) {
+            Ok(false)
+        } else if keys.len() >= max {
+            Err(())
+        } else {
+            keys.insert(key.to_owned());
+            Ok(true)
+        }
+    }
+
+    /// Stop counting `key` against the quota of `scope`.
+    pub(crate) fn release_storage_key(&self, scope: &str, key: &str) {
+        if let Some(keys) = self.storage_keys.borrow_mut().get_mut(scope) {
+            keys.remove(key);
+        }
+    }
+
+    /// Take the host calls recorded so far.
+    pub(crate) fn take_recording(&self) -> Option<Vec<HostCall>> {
+        self.recording.as_ref().map(|recording| recording.take())
+    }
+
+    /// Next value of the seeded random number generator.
+    pub(crate) fn next_random(&self) -> u64 {
+        self.rng.next()
+    }
+
+    /// Current wall-clock time in milliseconds, pinned in deterministic mode.
+    pub(crate) fn now(&self) -> VmResult<i64> {
+        let args = json!([]);
+
+        let now = match self.replayed("now", &args) {
+            Some(replayed) => vm_try!(replayed).as_i64().unwrap_or_default(),
+            None => match &self.deterministic {
+                Some(Deterministic { now: Some(now), .. }) => *now,
+                Some(_) => return VmResult::panic("wall-clock access is disabled in deterministic mode"),
+                None => self.host.now(),
+            },
+        };
+
+        self.record("now", args, json!(now));
+        VmResult::Ok(now)
+    }
+
+    /// Perform a host call, converting its result into a Rune value.
+    pub(crate) async fn call<F>(&self, name: &str, args: SerdeValue, live: F) -> VmResult<VmValue>
+    where
+        F: FnOnce(&dyn CybHost) -> HostFuture,
+    {
+        let result = vm_try!(self.call_json(name, args, live).await);
+        VmResult::Ok(map_to_rune_value(&result))
+    }
+
+    /// Perform a host call.
+    ///
+    /// When replaying, the call is answered from the replay bundle and in
+    /// deterministic mode from the snapshot. The host is only called when
+    /// neither applies.
+    pub(crate) async fn call_json<F>(&self, name: &str, args: SerdeValue, live: F) -> VmResult<SerdeValue>
+    where
+        F: FnOnce(&dyn CybHost) -> HostFuture,
+    {
+        let result = match self.replayed(name, &args) {
+            Some(replayed) => vm_try!(replayed),
+            None => match &self.deterministic {
+                Some(deterministic) => vm_try!(deterministic.lookup(name, &args)),
+                None => match live(&*self.host).await {
+                    Ok(result) => result,
+                    Err(error) => return VmResult::panic(format!("host call `{}` failed: {}", name, error)),
+                },
+            },
+        };
+
+        self.record(name, args, result.clone());
+        VmResult::Ok(result)
+    }
+
+    /// The next call from the replay bundle, which must match the call the
+    /// script is making.
+    fn replayed(&self, name: &str, args: &SerdeValue) -> Option<VmResult<SerdeValue>> {
+        let next = self.replay.as_ref()?.borrow_mut().pop_front();
+
+        Some(match next {
+            Some(call) if call.call == name && call.args == *args => VmResult::Ok(call.result),
+            Some(call) => VmResult::panic(format!(
+                "replay diverged: expected `{}({})` but the script called `{}({})`",
+                call.call, call.args, name, args
+            )),
+            None => VmResult::panic(format!(
+                "replay exhausted: no recorded result for `{}({})`",
+                name, args
+            )),
+        })
+    }
+
+    fn record(&self, name: &str, args: SerdeValue, result: SerdeValue) {
+        if let Some(recording) = &self.recording {
+            recording.borrow_mut().push(HostCall {
+                call: name.to_owned(),
+                args,
+                result,
+            });
+        }
+    }
+}
+
+/// Make `session` the current session while `future` is being polled.
+pub(crate) fn with<F>(session: Rc<Session>, future: F) -> WithSession<F>
+where
+    F: Future,
+{
+    WithSession {
+        session,
+        future: Box::pin(future),
+    }
+}
+
+/// Run `future` with `frames` as the call stack of `session`.
+///
+/// The stack is only swapped in while `future` is being polled, so nested
+/// scripts running concurrently each see their own stack, and nothing is left
+/// on it if `future` panics or is dropped before completing.
+pub(crate) fn with_frames<F>(session: Rc<Session>, frames: Vec<Frame>, future: F) -> WithFrames<F>
+where
+    F: Future,
+{
+    WithFrames {
+        session,
+        frames,
+        future: Box::pin(future),
+    }
+}
+
+/// Access the session of the script currently being run.
+pub(crate) fn current() -> VmResult<Rc<Session>> {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(session) => VmResult::Ok(session.clone()),
+        None => VmResult::panic("cyb functions can only be called from a running script"),
+    })
+}
+
+pub(crate) struct WithSession<F> {
+    session: Rc<Session>,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Future for WithSession<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let previous = CURRENT.with(|current| current.replace(Some(this.session.clone())));
+        let poll = this.future.as_mut().poll(cx);
+        this.session.collect_output();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        poll
+    }
+}
+
+pub(crate) struct WithFrames<F> {
+    session: Rc<Session>,
+    frames: Vec<Frame>,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Future for WithFrames<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let _frames = SwapFrames::new(&this.session, &mut this.frames);
+        this.future.as_mut().poll(cx)
+    }
+}
+
+/// Swaps a call stack into a session, and back out when dropped.
+struct SwapFrames<'a> {
+    session: &'a Session,
+    frames: &'a mut Vec<Frame>,
+}
+
+impl<'a> SwapFrames<'a> {
+    fn new(session: &'a Session, frames: &'a mut Vec<Frame>) -> Self {
+        std::mem::swap(&mut *session.frames.borrow_mut(), frames);
+        Self { session, frames }
+    }
+}
+
+impl Drop for SwapFrames<'_> {
+    fn drop(&mut self) {
+        std::mem::swap(&mut *self.session.frames.borrow_mut(), self.frames);
+    }
+}
@@ -0,0 +1,144 @@
+//! The `cyb::storage` module: key-value storage persisting between runs.
+//!
+//! Entries are scoped by script identity: the CID of a script evaluated
+//! through `cyb::eval_script_from_ipfs`, or the `scriptId` of the top-level
+//! run. The store itself is provided by the host, the crate only enforces
+//! quotas. Keys are listed once per scope and then counted by the run, so
+//! concurrent writes can't exceed the quota. Writing functions are left out
+//! of read-only runs.
+
+use rune::runtime::{Value as VmValue, VmResult};
+use rune::{vm_try, ContextError, Module};
+use serde_json::{json, Value as SerdeValue};
+
+use crate::helpers::{from_json, map_to_rune_value};
+use crate::session::{self, Session};
+
+/// Largest size of a key in bytes.
+const MAX_KEY_SIZE: usize = 256;
+
+/// Largest size of a value in bytes, once encoded as JSON.
+const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+/// Largest number of keys stored per script.
+const MAX_KEYS: usize = 1000;
+
+fn scope(session: &Session) -> VmResult<String> {
+    match session.scope() {
+        Some(scope) => VmResult::Ok(scope),
+        None => VmResult::panic("storage is only available to scripts with an id, set `scriptId` in the compiler params"),
+    }
+}
+
+fn check_key(key: &str) -> VmResult<()> {
+    if key.is_empty() || key.len() > MAX_KEY_SIZE {
+        return VmResult::panic(format!("storage keys must be 1 to {} bytes long", MAX_KEY_SIZE));
+    }
+
+    VmResult::Ok(())
+}
+
+async fn keys(session: &Session, scope: &str) -> VmResult<Vec<String>> {
+    let result = vm_try!(session.call_json("storage_list", json!([scope]), |host| host.storage_list(scope)).await);
+
+    match result {
+        SerdeValue::Null => VmResult::Ok(Vec::new()),
+        result => from_json("storage::list", result),
+    }
+}
+
+/// The value stored under `key`, `None` if there is none.
+async fn get(key: &str) -> VmResult<Option<VmValue>> {
+    let session = vm_try!(session::current());
+    let scope = vm_try!(scope(&session));
+    vm_try!(check_key(key));
+
+    let result = vm_try!(session.call_json("storage_get", json!([scope, key]), |host| host.storage_get(&scope, key)).await);
+
+    match result {
+        SerdeValue::Null => VmResult::Ok(None),
+        result => VmResult::Ok(Some(map_to_rune_value(&result))),
+    }
+}
+
+/// Store `value` under `key`, replacing any previous value.
+async fn set(key: &str, value: VmValue) -> VmResult<()> {
+    let session = vm_try!(session::current());
+    let scope = vm_try!(scope(&session));
+    vm_try!(check_key(key));
+
+    let value = match serde_json::to_value(&value) {
+        Ok(value) => value,
+        Err(error) => return VmResult::panic(format!("storage::set: value can't be stored: {}", error)),
+    };
+
+    let size = value.to_string().len();
+
+    if size > MAX_VALUE_SIZE {
+        return VmResult::panic(format!(
+            "storage::set: value takes {} bytes, at most {} are allowed",
+            size, MAX_VALUE_SIZE
+        ));
+    }
+
+    if !session.tracks_storage(&scope) {
+        let keys = vm_try!(keys(&session, &scope).await);
+        session.track_storage(&scope, keys);
+    }
+
+    // NB: the key is counted before the host is called, so that writes
+    // running concurrently see it.
+    let reserved = match session.reserve_storage_key(&scope, key, MAX_KEYS) {
+        Ok(reserved) => reserved,
+        Err(()) => return VmResult::panic(format!("storage::set: at most {} keys can be stored", MAX_KEYS)),
+    };
+
+    let args = json!([scope, key, value]);
+
+    match session.call_json("storage_set", args, |host| host.storage_set(&scope, key, &value)).await {
+        VmResult::Ok(_) => VmResult::Ok(()),
+        VmResult::Err(error) => {
+            if reserved {
+                session.release_storage_key(&scope, key);
+            }
+
+            VmResult::Err(error)
+        }
+    }
+}
+
+/// Remove the value stored under `key`, if any.
+async fn delete(key: &str) -> VmResult<()> {
+    let session = vm_try!(session::current());
+    let scope = vm_try!(scope(&session));
+    vm_try!(check_key(key));
+
+    vm_try!(session.call_json("storage_delete", json!([scope, key]), |host| host.storage_delete(&scope, key)).await);
+    session.release_storage_key(&scope, key);
+    VmResult::Ok(())
+}
+
+/// Every key stored by the script, in order.
+async fn list() -> VmResult<Vec<String>> {
+    let session = vm_try!(session::current());
+    let scope = vm_try!(scope(&session));
+
+    let mut keys = vm_try!(keys(&session, &scope).await);
+    keys.sort();
+    VmResult::Ok(keys)
+}
+
+/// The `cyb::storage` module.
+pub fn module(read_only: bool) -> Result<Module, ContextError> {
+    let mut module = Module::with_crate_item("cyb", ["storage"]);
+
+    module.function(["get"], get)?;
+    module.function(["list"], list)?;
+
+    if !read_only {
+        module.function(["set"], set)?;
+        module.function(["delete"], delete)?;
+    }
+
+    Ok(module)
+}
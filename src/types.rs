@@ -0,0 +1,523 @@
+//! Typed values returned to scripts by the `cyb` module.
+
+use rune::runtime::{Bytes, Protocol};
+use rune::{Any, ContextError, Module};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value as SerdeValue;
+
+use crate::helpers::map_to_rune_value;
+
+/// A cyberlink between two particles.
+#[derive(Any, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Cyberlink {
+    /// CID of the particle the link goes out of.
+    #[rune(get)]
+    #[serde(alias = "particle_from", alias = "particleFrom")]
+    pub(crate) from: String,
+    /// CID of the particle the link points to.
+    #[rune(get)]
+    #[serde(alias = "particle_to", alias = "particleTo")]
+    pub(crate) to: String,
+    /// Address of the neuron that created the link.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) neuron: String,
+    /// Block height the link was created at.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) height: i64,
+    /// Time the link was created at, as an RFC 3339 string.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) timestamp: Option<String>,
+}
+
+/// A page of cyberlinks.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct LinksPage {
+    /// The links on this page.
+    #[rune(get)]
+    pub(crate) links: Vec<Cyberlink>,
+    /// Offset of the first link on this page.
+    #[rune(get, copy)]
+    pub(crate) offset: i64,
+    /// Total number of links, if known by the host.
+    #[rune(get)]
+    pub(crate) total: Option<i64>,
+    /// Offset of the next page, `None` on the last page.
+    #[rune(get)]
+    pub(crate) next: Option<i64>,
+}
+
+/// A particle found by `cyb::search`.
+#[derive(Any, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SearchResult {
+    /// CID of the particle.
+    #[rune(get)]
+    #[serde(alias = "particle")]
+    pub(crate) cid: String,
+    /// Cyberrank of the particle.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "number")]
+    pub(crate) rank: f64,
+    /// MIME type of the particle content, if known.
+    #[rune(get)]
+    #[serde(default, alias = "contentType")]
+    pub(crate) content_type: Option<String>,
+    /// Address of the neuron that linked the particle, if known.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) neuron: Option<String>,
+    /// Time the particle was linked at, as an RFC 3339 string.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) timestamp: Option<String>,
+}
+
+/// A page of search results.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct SearchPage {
+    /// The results on this page.
+    #[rune(get)]
+    pub(crate) results: Vec<SearchResult>,
+    /// Offset of the first result on this page.
+    #[rune(get, copy)]
+    pub(crate) offset: i64,
+    /// Total number of results, if known by the host.
+    #[rune(get)]
+    pub(crate) total: Option<i64>,
+    /// Offset of the next page, `None` on the last page.
+    #[rune(get)]
+    pub(crate) next: Option<i64>,
+}
+
+/// Statistics of a particle returned by `cyb::particle_stats`.
+#[derive(Any, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ParticleStats {
+    /// CID of the particle.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) cid: String,
+    /// Cyberrank of the particle.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "number")]
+    pub(crate) rank: f64,
+    /// Number of cyberlinks pointing to the particle.
+    #[rune(get, copy)]
+    #[serde(default)]
+    pub(crate) inbound: i64,
+    /// Number of cyberlinks going out of the particle.
+    #[rune(get, copy)]
+    #[serde(default)]
+    pub(crate) outbound: i64,
+    /// Addresses of the neurons that linked the particle.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) neurons: Vec<String>,
+}
+
+/// A balance of a single denomination.
+#[derive(Any, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Coin {
+    /// Denomination, e.g. `boot`.
+    #[rune(get)]
+    pub(crate) denom: String,
+    /// Exact amount in the smallest unit of the denomination, as a decimal
+    /// string since amounts are 128-bit.
+    #[rune(get)]
+    #[serde(deserialize_with = "amount")]
+    pub(crate) amount: String,
+}
+
+/// Energy of a neuron returned by `cyb::neuron_energy`.
+#[derive(Any, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct NeuronEnergy {
+    /// Address of the neuron.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) address: String,
+    /// Volts held by the neuron, in millivolts.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) volts: i64,
+    /// Amperes held by the neuron, in milliamperes.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) amperes: i64,
+    /// Bandwidth left for creating cyberlinks.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) bandwidth_remaining: i64,
+    /// Bandwidth available when fully recovered.
+    #[rune(get, copy)]
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) bandwidth_max: i64,
+}
+
+/// A passport, the on-chain identity of a neuron.
+///
+/// Hosts may answer with the passport contract's shape, where the nickname,
+/// avatar and addresses live in `extension`, or with those fields inlined.
+#[derive(Any, Clone, Debug, Deserialize)]
+#[serde(from = "RawPassport")]
+pub(crate) struct Passport {
+    /// Id of the passport token.
+    #[rune(get)]
+    pub(crate) token_id: String,
+    /// Nickname of the passport.
+    #[rune(get)]
+    pub(crate) nickname: String,
+    /// Address of the neuron owning the passport.
+    #[rune(get)]
+    pub(crate) owner: String,
+    /// CID of the avatar particle, if any.
+    #[rune(get)]
+    pub(crate) avatar: Option<String>,
+    /// Addresses proved by the passport.
+    #[rune(get)]
+    pub(crate) addresses: Vec<String>,
+    /// The extension data as returned by the host.
+    pub(crate) extension: SerdeValue,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PassportAddress {
+    Plain(String),
+    Labeled { address: String },
+}
+
+#[derive(Deserialize)]
+struct RawPassport {
+    #[serde(default, alias = "tokenId")]
+    token_id: SerdeValue,
+    #[serde(default)]
+    owner: String,
+    #[serde(default)]
+    nickname: Option<String>,
+    #[serde(default)]
+    avatar: Option<String>,
+    #[serde(default)]
+    addresses: Option<Vec<PassportAddress>>,
+    #[serde(default)]
+    extension: SerdeValue,
+}
+
+impl From<RawPassport> for Passport {
+    fn from(raw: RawPassport) -> Self {
+        let extension = &raw.extension;
+
+        let string = |value: &SerdeValue| value.as_str().map(str::to_owned);
+
+        let token_id = match raw.token_id {
+            SerdeValue::String(id) => id,
+            SerdeValue::Number(id) => id.to_string(),
+            _ => String::new(),
+        };
+
+        let addresses = raw.addresses.unwrap_or_else(|| {
+            serde_json::from_value(extension["addresses"].clone()).unwrap_or_default()
+        });
+
+        Self {
+            token_id,
+            nickname: raw.nickname.or_else(|| string(&extension["nickname"])).unwrap_or_default(),
+            owner: raw.owner,
+            avatar: raw.avatar.or_else(|| string(&extension["avatar"])),
+            addresses: addresses
+                .into_iter()
+                .map(|address| match address {
+                    PassportAddress::Plain(address) | PassportAddress::Labeled { address } => address,
+                })
+                .collect(),
+            extension: raw.extension,
+        }
+    }
+}
+
+/// A neuron along with its passport, returned by `cyb::me`.
+#[derive(Any, Clone, Debug, Deserialize)]
+pub(crate) struct Neuron {
+    /// Address of the neuron.
+    #[rune(get)]
+    pub(crate) address: String,
+    /// Passport of the neuron, if it has one.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) passport: Option<Passport>,
+}
+
+/// Content of a particle returned by `cyb::ipfs_get`.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct IpfsContent {
+    /// CID of the particle.
+    #[rune(get)]
+    pub(crate) cid: String,
+    /// MIME type of the content.
+    #[rune(get)]
+    pub(crate) content_type: String,
+    /// Size of the content in bytes.
+    #[rune(get, copy)]
+    pub(crate) size: i64,
+    /// The content as text, `None` if it isn't valid UTF-8.
+    #[rune(get)]
+    pub(crate) text: Option<String>,
+    /// The raw content.
+    pub(crate) bytes: Vec<u8>,
+    /// The parsed content for JSON particles, `null` otherwise.
+    pub(crate) json: SerdeValue,
+}
+
+impl IpfsContent {
+    /// Fill in whatever the host left out of its answer.
+    pub(crate) fn new(cid: String, raw: RawIpfsContent) -> Self {
+        let bytes = match (raw.bytes, &raw.text) {
+            (Some(bytes), _) => bytes,
+            (None, Some(text)) => text.as_bytes().to_vec(),
+            (None, None) => Vec::new(),
+        };
+
+        let text = raw.text.or_else(|| String::from_utf8(bytes.clone()).ok());
+
+        let json = match (&raw.content_type, &text) {
+            (Some(content_type), Some(text)) if content_type.contains("json") => {
+                serde_json::from_str(text).unwrap_or_default()
+            }
+            (None, Some(text)) if text.trim_start().starts_with(['{', '[']) => {
+                serde_json::from_str(text).unwrap_or_default()
+            }
+            _ => SerdeValue::Null,
+        };
+
+        let content_type = raw.content_type.unwrap_or_else(|| {
+            if !json.is_null() {
+                "application/json"
+            } else if text.is_some() {
+                "text/plain"
+            } else {
+                "application/octet-stream"
+            }
+            .to_owned()
+        });
+
+        Self {
+            cid,
+            content_type,
+            size: raw.size.unwrap_or(bytes.len() as i64),
+            text,
+            bytes,
+            json,
+        }
+    }
+}
+
+/// Content of a particle as answered by the host.
+#[derive(Deserialize)]
+pub(crate) struct RawIpfsContent {
+    #[serde(default, alias = "contentType")]
+    content_type: Option<String>,
+    #[serde(default)]
+    size: Option<i64>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    bytes: Option<Vec<u8>>,
+}
+
+/// Metadata of a particle returned by `cyb::ipfs_stat`.
+#[derive(Any, Clone, Debug, Deserialize)]
+pub(crate) struct IpfsStat {
+    /// CID of the particle.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) cid: String,
+    /// MIME type of the content, if known.
+    #[rune(get)]
+    #[serde(default, alias = "contentType")]
+    pub(crate) content_type: Option<String>,
+    /// Size of the content in bytes, if known.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) size: Option<i64>,
+}
+
+/// A script on the call stack returned by `cyb::call_stack`.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct StackFrame {
+    /// CID of the script, `None` for the top-level script.
+    #[rune(get)]
+    pub(crate) cid: Option<String>,
+    /// The function being run.
+    #[rune(get)]
+    pub(crate) func_name: String,
+}
+
+/// Tokens consumed by an LLM call.
+#[derive(Any, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Usage {
+    /// Tokens of the messages sent.
+    #[rune(get, copy)]
+    #[serde(alias = "promptTokens")]
+    pub(crate) prompt_tokens: i64,
+    /// Tokens generated.
+    #[rune(get, copy)]
+    #[serde(alias = "completionTokens")]
+    pub(crate) completion_tokens: i64,
+    /// Sum of both.
+    #[rune(get, copy)]
+    #[serde(alias = "totalTokens")]
+    pub(crate) total_tokens: i64,
+}
+
+/// An answer returned by `cyb::llm::chat`.
+#[derive(Any, Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChatResponse {
+    /// Text of the answer.
+    #[rune(get)]
+    pub(crate) content: String,
+    /// Why generation stopped, e.g. `stop` or `length`, if the host tells.
+    #[rune(get)]
+    #[serde(alias = "finishReason")]
+    pub(crate) finish_reason: Option<String>,
+    /// Model that answered, if the host tells.
+    #[rune(get)]
+    pub(crate) model: Option<String>,
+    /// Tokens consumed.
+    #[rune(get)]
+    #[serde(default)]
+    pub(crate) usage: Usage,
+}
+
+/// The final answer of `cyb::llm::agent`.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct AgentResponse {
+    /// Text of the answer.
+    #[rune(get)]
+    pub(crate) content: String,
+    /// Why generation stopped, if the host tells.
+    #[rune(get)]
+    pub(crate) finish_reason: Option<String>,
+    /// Model that answered, if the host tells.
+    #[rune(get)]
+    pub(crate) model: Option<String>,
+    /// Tokens consumed over every step.
+    #[rune(get)]
+    pub(crate) usage: Usage,
+    /// Number of tool calls executed.
+    #[rune(get, copy)]
+    pub(crate) tool_calls: i64,
+    /// The whole conversation including tool calls and their results, as
+    /// `#{ role, content, tool_calls, tool_call_id }` objects.
+    pub(crate) messages: SerdeValue,
+}
+
+/// A secret held by the host, e.g. an API key, returned by `cyb::secret`.
+///
+/// Scripts can pass it to host functions but never read it: its value is
+/// only resolved by the crate when a host call needs it.
+#[derive(Any, Clone, Debug)]
+pub(crate) struct Secret {
+    /// Name the host knows the secret by.
+    pub(crate) name: String,
+}
+
+/// Deserialize an exact integer which hosts may send as a JSON integer or an
+/// integer string, failing instead of losing precision.
+pub(crate) fn integer<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Integer {
+        Integer(i64),
+        String(String),
+    }
+
+    match Integer::deserialize(deserializer)? {
+        Integer::Integer(integer) => Ok(integer),
+        Integer::String(string) => string
+            .trim()
+            .parse()
+            .map_err(|_| de::Error::custom(format!("`{}` is not an integer fitting in 64 bits", string))),
+    }
+}
+
+/// Deserialize a 128-bit amount which hosts may send as a JSON integer or an
+/// integer string, into its decimal string.
+pub(crate) fn amount<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Integer(u64),
+        String(String),
+    }
+
+    let amount = match Amount::deserialize(deserializer)? {
+        Amount::Integer(integer) => u128::from(integer),
+        Amount::String(string) => string
+            .trim()
+            .parse()
+            .map_err(|_| de::Error::custom(format!("`{}` is not an amount fitting in 128 bits", string)))?,
+    };
+
+    Ok(amount.to_string())
+}
+
+/// Deserialize a number which hosts may send as a JSON number, a numeric
+/// string or `null`.
+pub(crate) fn number<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Number(f64),
+        String(String),
+        Null(()),
+    }
+
+    match Number::deserialize(deserializer)? {
+        Number::Number(number) => Ok(number),
+        Number::String(string) => string.trim().parse().map_err(de::Error::custom),
+        Number::Null(()) => Ok(0.0),
+    }
+}
+
+/// Install the typed values into the `cyb` module.
+pub(crate) fn install(module: &mut Module) -> Result<(), ContextError> {
+    module.ty::<Cyberlink>()?;
+    module.ty::<LinksPage>()?;
+    module.ty::<SearchResult>()?;
+    module.ty::<SearchPage>()?;
+    module.ty::<ParticleStats>()?;
+    module.ty::<Coin>()?;
+    module.ty::<NeuronEnergy>()?;
+    module.ty::<Passport>()?;
+    module.ty::<Neuron>()?;
+    module.field_fn(Protocol::GET, "extension", |passport: &Passport| {
+        map_to_rune_value(&passport.extension)
+    })?;
+    module.ty::<IpfsContent>()?;
+    module.ty::<IpfsStat>()?;
+    module.ty::<StackFrame>()?;
+    module.ty::<Secret>()?;
+    module.ty::<Usage>()?;
+    module.ty::<ChatResponse>()?;
+    module.ty::<AgentResponse>()?;
+    module.field_fn(Protocol::GET, "messages", |response: &AgentResponse| {
+        map_to_rune_value(&response.messages)
+    })?;
+    module.field_fn(Protocol::GET, "bytes", |content: &IpfsContent| {
+        Bytes::from_vec(content.bytes.clone())
+    })?;
+    module.field_fn(Protocol::GET, "json", |content: &IpfsContent| {
+        map_to_rune_value(&content.json)
+    })?;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use std::rc::Rc;
+
+use cyb_rune_wasm::host::{MemoryHost, ToolCall};
+use serde_json::json;
+
+const SCRIPT: &str = r#"
+/// Search the notes about `query`.
+pub fn search_notes(query) {
+    format!("notes about {}", query)
+}
+
+pub async fn main() {
+    let answer = cyb::llm::agent([#{ role: "user", content: "find notes" }], ["search_notes"]).await;
+    let tool = answer.messages[2];
+    format!("{}|{}|{}", answer.content, answer.tool_calls, tool["content"])
+}
+"#;
+
+/// A host answering "find notes" after calling `search_notes` with
+/// `arguments`.
+fn host(arguments: serde_json::Value) -> Rc<MemoryHost> {
+    common::host(|host| {
+        host.add_prompt("find notes", "found them");
+
+        host.add_tool_calls("find notes", vec![ToolCall {
+            id: String::new(),
+            name: "search_notes".to_owned(),
+            arguments,
+        }]);
+    })
+}
+
+#[test]
+fn calls_tools_until_answered() {
+    let result = common::run(&host(json!({ "query": "rune" })), SCRIPT);
+    let result = common::result(&result);
+    assert!(result.contains("found them|1|"), "{}", result);
+    assert!(result.contains("notes about rune"), "{}", result);
+}
+
+#[test]
+fn parses_arguments_given_as_json_string() {
+    let result = common::run(&host(json!("{\"query\":\"rune\"}")), SCRIPT);
+    let result = common::result(&result);
+    assert!(result.contains("found them|1|"), "{}", result);
+    assert!(result.contains("notes about rune"), "{}", result);
+}
+
+#[test]
+fn refuses_running_function_as_tool() {
+    let script = r#"
+        pub async fn main() {
+            cyb::llm::agent([#{ role: "user", content: "find notes" }], ["main"]).await
+        }
+    "#;
+
+    let result = common::run(&host(json!({})), script);
+    assert!(common::error(&result).contains("can't be a tool"));
+}
+
+#[test]
+fn caps_max_steps() {
+    let script = r#"
+        pub async fn main() {
+            cyb::llm::agent_with([#{ role: "user", content: "find notes" }], [], #{ max_steps: 1000 }).await
+        }
+    "#;
+
+    let result = common::run(&host(json!({})), script);
+    assert!(common::error(&result).contains("max_steps is limited to 32"));
+}
+
+#[test]
+fn refuses_function_on_call_stack_as_tool() {
+    let script = r#"
+        pub async fn search_notes(query) {
+            cyb::llm::agent([#{ role: "user", content: query }], ["search_notes"]).await
+        }
+
+        pub async fn main() {
+            cyb::llm::agent([#{ role: "user", content: "find notes" }], ["search_notes"]).await
+        }
+    "#;
+
+    let result = common::run(&host(json!({ "query": "rune" })), script);
+    assert!(common::error(&result).contains("`search_notes` is running the agent and can't be a tool"));
+}
+
+#[test]
+fn answers_within_max_steps() {
+    let script = r#"
+        pub fn search_notes(query) {
+            format!("notes about {}", query)
+        }
+
+        pub async fn main() {
+            let answer = cyb::llm::agent_with([#{ role: "user", content: "find notes" }], ["search_notes"], #{ max_steps: 1 }).await;
+            format!("{}|{}", answer.content, answer.tool_calls)
+        }
+    "#;
+
+    let result = common::run(&host(json!({ "query": "rune" })), script);
+    assert!(common::result(&result).contains("found them|1"));
+}
+
+#[test]
+fn runs_no_tools_past_max_steps() {
+    let script = r#"
+        pub fn search_notes(query) {
+            println!("searched {}", query);
+        }
+
+        pub async fn main() {
+            cyb::llm::agent_with([#{ role: "user", content: "find notes" }], ["search_notes"], #{ max_steps: 0 }).await
+        }
+    "#;
+
+    let result = common::run(&host(json!({ "query": "rune" })), script);
+    assert!(common::error(&result).contains("no answer after 0 rounds of tool calls"));
+    assert!(!result["output"].as_str().unwrap_or_default().contains("searched"));
+}
+
+#[test]
+fn refuses_tool_messages() {
+    let script = r#"
+        pub async fn main() {
+            cyb::llm::agent([#{ role: "tool", content: "notes about rune" }], []).await
+        }
+    "#;
+
+    let result = common::run(&host(json!({})), script);
+    assert!(common::error(&result).contains("`role` must be `system`, `user` or `assistant`"));
+}
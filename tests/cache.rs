@@ -0,0 +1,67 @@
+mod common;
+
+use serde_json::{json, Value as SerdeValue};
+
+/// Hits and misses of the unit cache while running `f`.
+fn counted<T>(f: impl FnOnce() -> T) -> (T, u64, u64) {
+    let before = cyb_rune_wasm::cache_stats();
+    let value = f();
+    let after = cyb_rune_wasm::cache_stats();
+    (value, after.hits() - before.hits(), after.misses() - before.misses())
+}
+
+fn run_with_app(script: &str, name: &str) -> SerdeValue {
+    let params = json!({ "app": { "name": name } });
+    common::run_with(&common::host(|_| {}), script, params, common::compiler_params(json!({})))
+}
+
+#[test]
+fn reuses_units_of_same_run_params() {
+    let script = r#"
+        pub fn main() {
+            let app = cyb::context::app;
+            format!("same {}", app["name"])
+        }
+    "#;
+
+    let (results, hits, misses) = counted(|| [run_with_app(script, "first"), run_with_app(script, "first")]);
+
+    for result in &results {
+        assert!(common::result(result).contains("same first"), "{}", result);
+    }
+
+    assert_eq!((hits, misses), (1, 1));
+}
+
+#[test]
+fn compiles_context_constants_per_app() {
+    let script = r#"
+        pub fn main() {
+            let app = cyb::context::app;
+            format!("per app {}", app["name"])
+        }
+    "#;
+
+    let (results, hits, misses) = counted(|| [run_with_app(script, "first"), run_with_app(script, "second")]);
+    assert!(common::result(&results[0]).contains("first"), "{}", results[0]);
+    assert!(common::result(&results[1]).contains("second"), "{}", results[1]);
+    assert_eq!((hits, misses), (0, 2));
+}
+
+#[test]
+fn keeps_diagnostics_of_cached_units() {
+    let script = r#"
+        pub fn main() {
+            let unused = 1;
+            2
+        }
+    "#;
+
+    let host = common::host(|_| {});
+    let ((first, second), hits, misses) = counted(|| (common::run(&host, script), common::run(&host, script)));
+
+    assert_eq!((hits, misses), (1, 1));
+    assert!(common::result(&first).contains('2'));
+    assert_eq!(first["diagnostics"], second["diagnostics"]);
+    assert_eq!(first["diagnosticsOutput"], second["diagnosticsOutput"]);
+}
@@ -0,0 +1,93 @@
+//! Helpers shared by the integration tests: scripts are run with
+//! [cyb_rune_wasm::run] against a [MemoryHost].
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use cyb_rune_wasm::host::MemoryHost;
+use cyb_rune_wasm::CompilerParams;
+use serde_json::{json, Value as SerdeValue};
+
+/// Drive a future to completion on the current thread.
+pub fn block_on<F>(future: F) -> F::Output
+where
+    F: Future,
+{
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// A [MemoryHost] set up by `setup`.
+pub fn host<F>(setup: F) -> Rc<MemoryHost>
+where
+    F: FnOnce(&mut MemoryHost),
+{
+    let mut host = MemoryHost::new();
+    setup(&mut host);
+    Rc::new(host)
+}
+
+/// Compiler params executing `main` with `config`.
+pub fn compiler_params(config: SerdeValue) -> SerdeValue {
+    json!({
+        "readOnly": false,
+        "scriptId": "test",
+        "funcName": "main",
+        "funcParams": [],
+        "execute": true,
+        "config": config,
+    })
+}
+
+/// Run `script` with run `params` and `compiler_params`, returning the result
+/// as the JSON handed to JS.
+pub fn run_with(host: &Rc<MemoryHost>, script: &str, params: SerdeValue, compiler_params: SerdeValue) -> SerdeValue {
+    let compiler_params: CompilerParams = serde_json::from_value(compiler_params).unwrap();
+
+    let result = block_on(cyb_rune_wasm::run(
+        script.to_owned(),
+        String::new(),
+        params,
+        compiler_params,
+        host.clone(),
+    ));
+
+    serde_json::to_value(&result).unwrap()
+}
+
+/// Run `main` of `script`.
+pub fn run(host: &Rc<MemoryHost>, script: &str) -> SerdeValue {
+    run_with(host, script, json!({}), compiler_params(json!({})))
+}
+
+/// The value `main` returned, failing the test if the run failed.
+pub fn result(result: &SerdeValue) -> &str {
+    assert!(result["error"].is_null(), "run failed: {}", result);
+    result["result"].as_str().unwrap()
+}
+
+/// The error the run failed with, failing the test if it didn't.
+pub fn error(result: &SerdeValue) -> &str {
+    result["error"].as_str().unwrap_or_else(|| panic!("run didn't fail: {}", result))
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use serde_json::json;
+
+/// A CID scripts can be stored under.
+const CID: &str = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+
+#[test]
+fn detects_cycles() {
+    let source = r#"pub async fn main() { cyb::eval_script_from_ipfs("QmLoop", "main", []).await }"#;
+    let host = common::host(|host| host.add_particle("QmLoop", source));
+
+    let result = common::run(&host, source);
+    assert!(common::error(&result).contains("script cycle detected"));
+}
+
+#[test]
+fn detects_top_level_script_evaluating_itself() {
+    let source = format!(
+        r#"pub async fn main() {{ println!("entered"); cyb::eval_script_from_ipfs("{}", "main", []).await }}"#,
+        CID
+    );
+
+    let host = common::host(|host| host.add_particle(CID, source.as_str()));
+
+    let mut params = common::compiler_params(json!({}));
+    params["scriptId"] = json!(CID);
+
+    let result = common::run_with(&host, &source, json!({}), params);
+    let error = common::error(&result);
+    assert!(error.contains(&format!("script cycle detected: {} -> {}", CID, CID)), "{}", error);
+    assert_eq!(result["output"].as_str().unwrap_or_default().matches("entered").count(), 1);
+}
+
+#[test]
+fn limits_depth() {
+    let host = common::host(|host| {
+        for i in 0..4 {
+            let source = format!(r#"pub async fn main() {{ cyb::eval_script_from_ipfs("Qm{}", "main", []).await }}"#, i + 1);
+            host.add_particle(format!("Qm{}", i), source);
+        }
+    });
+
+    let script = r#"pub async fn main() { cyb::eval_script_from_ipfs("Qm0", "main", []).await }"#;
+    let params = common::compiler_params(json!({ "max_eval_depth": 2 }));
+    let result = common::run_with(&host, script, json!({}), params);
+    assert!(common::error(&result).contains("limited to a depth of 2"), "{}", result);
+}
@@ -0,0 +1,62 @@
+mod common;
+
+#[test]
+fn runs_nested_scripts_in_same_vm() {
+    let host = common::host(|host| host.add_particle("QmDouble", "pub fn double(n) { n * 2 }"));
+
+    let script = r#"
+        pub async fn main() {
+            cyb::eval_script_from_ipfs("QmDouble", "double", [21]).await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::result(&result).contains("42"), "{}", result);
+}
+
+#[test]
+fn reports_position_of_nested_errors() {
+    let host = common::host(|host| host.add_particle("QmFail", "pub fn main() {\n    panic(\"nope\")\n}"));
+
+    let script = r#"
+        pub async fn main() {
+            cyb::eval_script_from_ipfs("QmFail", "main", []).await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    let error = common::error(&result);
+    assert!(error.contains("QmFail:2:"), "{}", error);
+    assert!(error.contains("nope"), "{}", error);
+}
+
+#[test]
+fn narrows_nested_scripts_to_read_only() {
+    let host = common::host(|host| {
+        host.add_particle("QmWrite", r#"pub async fn main() { cyb::storage::set("key", 1).await }"#);
+    });
+
+    let script = r#"
+        pub async fn main() {
+            cyb::eval_script_from_ipfs_with("QmWrite", "main", [], #{ read_only: true }).await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::error(&result).contains("QmWrite"));
+    assert!(host.storage("QmWrite").is_empty());
+}
+
+#[test]
+fn fails_for_missing_scripts() {
+    let host = common::host(|_| {});
+
+    let script = r#"
+        pub async fn main() {
+            cyb::eval_script_from_ipfs("QmMissing", "main", []).await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::error(&result).contains("script `QmMissing` not found"));
+}
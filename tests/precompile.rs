@@ -0,0 +1,96 @@
+mod common;
+
+use std::thread;
+
+use cyb_rune_wasm::CompilerParams;
+use serde_json::{json, Value as SerdeValue};
+
+const SIGNING_KEY: &[u8] = b"0123456789abcdef";
+
+const SCRIPT: &str = r#"
+pub fn main() {
+    let app = cyb::context::app;
+    app["name"]
+}
+"#;
+
+fn params() -> SerdeValue {
+    json!({ "app": { "name": "precompiled" } })
+}
+
+fn compiler_params(config: SerdeValue) -> CompilerParams {
+    serde_json::from_value(common::compiler_params(config)).unwrap()
+}
+
+/// Precompile [SCRIPT] with `config` on another thread, so the unit isn't in
+/// the cache of the test's thread.
+fn precompile(config: SerdeValue) -> Vec<u8> {
+    thread::spawn(move || {
+        cyb_rune_wasm::precompile_unit(SCRIPT.to_owned(), String::new(), params(), SIGNING_KEY, compiler_params(config))
+            .unwrap()
+    })
+    .join()
+    .unwrap()
+}
+
+fn run_precompiled(precompiled: &[u8], signing_key: &[u8], params: SerdeValue, config: SerdeValue) -> SerdeValue {
+    let result = common::block_on(cyb_rune_wasm::run_precompiled(
+        SCRIPT.to_owned(),
+        String::new(),
+        precompiled,
+        signing_key,
+        params,
+        compiler_params(config),
+        common::host(|_| {}),
+    ));
+
+    serde_json::to_value(&result).unwrap()
+}
+
+/// Whether the precompiled unit was ignored, going by the warning.
+fn ignored(result: &SerdeValue) -> bool {
+    let output = result["diagnosticsOutput"].as_str().unwrap_or_default();
+    output.contains("precompiled unit ignored")
+}
+
+#[test]
+fn runs_precompiled_units() {
+    let precompiled = precompile(json!({}));
+
+    let result = run_precompiled(&precompiled, SIGNING_KEY, params(), json!({}));
+    assert!(common::result(&result).contains("precompiled"), "{}", result);
+    assert!(!ignored(&result), "{}", result);
+}
+
+#[test]
+fn runs_units_precompiled_for_recording() {
+    let precompiled = precompile(json!({ "record": true }));
+
+    let result = run_precompiled(&precompiled, SIGNING_KEY, params(), json!({ "record": true }));
+    assert!(common::result(&result).contains("precompiled"), "{}", result);
+    assert!(!ignored(&result), "{}", result);
+}
+
+#[test]
+fn ignores_units_signed_with_other_key() {
+    let precompiled = precompile(json!({}));
+
+    let result = run_precompiled(&precompiled, b"fedcba9876543210", params(), json!({}));
+    assert!(common::result(&result).contains("precompiled"), "{}", result);
+    assert!(ignored(&result), "{}", result);
+}
+
+#[test]
+fn ignores_units_precompiled_for_other_params() {
+    let precompiled = precompile(json!({}));
+
+    let result = run_precompiled(&precompiled, SIGNING_KEY, json!({ "app": { "name": "other" } }), json!({}));
+    assert!(common::result(&result).contains("other"), "{}", result);
+    assert!(ignored(&result), "{}", result);
+}
+
+#[test]
+fn refuses_short_signing_keys() {
+    let result = cyb_rune_wasm::precompile_unit(SCRIPT.to_owned(), String::new(), params(), b"short", compiler_params(json!({})));
+    assert!(result.is_err());
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::rc::Rc;
+
+use cyb_rune_wasm::host::MemoryHost;
+use serde_json::{json, Value as SerdeValue};
+
+const SCRIPT: &str = r#"
+pub async fn main() {
+    let text = cyb::get_text_from_ipfs("QmText").await;
+    format!("{}|{}", text, rand::int())
+}
+"#;
+
+/// Run `script` recording its host calls.
+fn record(host: &Rc<MemoryHost>, script: &str) -> SerdeValue {
+    common::run_with(host, script, json!({}), common::compiler_params(json!({ "record": true })))
+}
+
+/// Run `script` from `bundle`, with a host answering nothing.
+fn replay(script: &str, bundle: &SerdeValue) -> SerdeValue {
+    let host = common::host(|_| {});
+    let config = json!({ "replay": bundle });
+    common::run_with(&host, script, json!({}), common::compiler_params(config))
+}
+
+#[test]
+fn replays_host_calls_and_rand_without_host() {
+    let host = common::host(|host| host.add_particle("QmText", "hello"));
+    let recorded = record(&host, SCRIPT);
+    let expected = common::result(&recorded).to_owned();
+    assert!(expected.contains("hello|"), "{}", expected);
+
+    let bundle = &recorded["replay"];
+    assert_eq!(bundle["calls"][0]["call"], "get_text_from_ipfs");
+
+    let replayed = replay(SCRIPT, bundle);
+    assert_eq!(common::result(&replayed), expected);
+}
+
+#[test]
+fn replays_recorded_params() {
+    let script = r#"
+        pub fn main() {
+            cyb::context::particle
+        }
+    "#;
+
+    let host = common::host(|_| {});
+    let params = json!({ "particle": "QmParticle" });
+    let recorded = common::run_with(&host, script, params, common::compiler_params(json!({ "record": true })));
+    assert!(common::result(&recorded).contains("QmParticle"));
+
+    let replayed = replay(script, &recorded["replay"]);
+    assert!(common::result(&replayed).contains("QmParticle"));
+}
+
+#[test]
+fn refuses_bundle_from_other_sources() {
+    let recorded = record(&common::host(|_| {}), SCRIPT);
+
+    let other = SCRIPT.replace("QmText", "QmOther");
+    let replayed = replay(&other, &recorded["replay"]);
+    assert!(common::error(&replayed).contains("recorded from other sources or settings"));
+}
+
+#[test]
+fn fails_on_calls_missing_from_bundle() {
+    let host = common::host(|host| host.add_particle("QmText", "hello"));
+    let recorded = record(&host, SCRIPT);
+
+    let mut bundle = recorded["replay"].clone();
+    bundle["calls"] = json!([]);
+
+    let replayed = replay(SCRIPT, &bundle);
+    assert!(common::error(&replayed).contains("replay exhausted"), "{}", replayed);
+}
+
+#[test]
+fn leaves_http_out_of_recorded_runs() {
+    let script = r#"
+        pub async fn main() {
+            http::get("https://cyb.ai").await
+        }
+    "#;
+
+    let recorded = record(&common::host(|_| {}), script);
+    common::error(&recorded);
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use std::rc::Rc;
+
+use cyb_rune_wasm::host::MemoryHost;
+use serde_json::json;
+
+const SECRET: &str = "sk-0123456789";
+
+/// A host holding [SECRET] and leaking it when prompted with "leak", set up
+/// further by `setup`.
+fn host(setup: impl FnOnce(&mut MemoryHost)) -> Rc<MemoryHost> {
+    common::host(|host| {
+        host.add_secret("openai", SECRET);
+        host.add_prompt("leak", format!("the key is {}", SECRET));
+        setup(host);
+    })
+}
+
+#[test]
+fn redacts_resolved_secrets() {
+    let host = host(|_| {});
+
+    let script = r#"
+        pub async fn main() {
+            let answer = cyb::open_ai_prompt("leak", cyb::secret("openai")).await;
+            println!("{}", answer);
+            cyb::log(answer);
+            answer
+        }
+    "#;
+
+    let compiler_params = common::compiler_params(json!({ "record": true }));
+    let result = common::run_with(&host, script, json!({}), compiler_params);
+    assert!(common::result(&result).contains("[redacted]"));
+
+    let serialized = result.to_string();
+    assert!(!serialized.contains(SECRET), "{}", serialized);
+    assert!(result["output"].as_str().unwrap().contains("the key is [redacted]"));
+    assert!(result["replay"]["calls"].to_string().contains("[redacted]"));
+
+    assert!(host.logs().iter().any(|log| log == "the key is [redacted]"));
+}
+
+#[test]
+fn redacts_plain_keys() {
+    let host = host(|_| {});
+
+    let script = r#"
+        pub async fn main() {
+            cyb::open_ai_prompt("leak", "sk-0123456789").await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::result(&result).contains("[redacted]"));
+    assert!(!result.to_string().contains(SECRET));
+}
+
+#[test]
+fn leaves_short_plain_keys() {
+    let host = host(|host| host.add_prompt("short", "abc is fine"));
+
+    let script = r#"
+        pub async fn main() {
+            cyb::open_ai_prompt("short", "abc").await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::result(&result).contains("abc is fine"));
+}
+
+#[test]
+fn refuses_plain_keys_while_streaming() {
+    let host = host(|host| host.stream_output());
+
+    let script = r#"
+        pub async fn main() {
+            cyb::open_ai_prompt("leak", "sk-0123456789").await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::error(&result).contains("can't be used while output is streamed"));
+}
+
+#[test]
+fn resolves_secrets_only_when_live() {
+    let host = host(|_| {});
+
+    let script = r#"
+        pub async fn main() {
+            cyb::open_ai_prompt("leak", cyb::secret("openai")).await
+        }
+    "#;
+
+    let recorded = common::run_with(&host, script, json!({}), common::compiler_params(json!({ "record": true })));
+
+    // NB: the replaying host holds no secret, so resolving it would fail.
+    let replaying = common::host(|_| {});
+    let config = json!({ "replay": recorded["replay"] });
+    let replayed = common::run_with(&replaying, script, json!({}), common::compiler_params(config));
+    assert!(common::result(&replayed).contains("[redacted]"));
+}
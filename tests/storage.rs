@@ -0,0 +1,117 @@
+mod common;
+
+use std::rc::Rc;
+
+use cyb_rune_wasm::host::MemoryHost;
+use serde_json::{json, Value as SerdeValue};
+
+/// A host whose `test` script already stores `count` keys.
+fn host(count: usize) -> Rc<MemoryHost> {
+    common::host(|host| {
+        for i in 0..count {
+            host.set_storage("test", format!("key{}", i), json!(i));
+        }
+    })
+}
+
+#[test]
+fn stores_values_by_script() {
+    let host = host(0);
+
+    let script = r#"
+        pub async fn main() {
+            cyb::storage::set("seen", ["QmA", "QmB"]).await;
+            cyb::storage::set("gone", 1).await;
+            cyb::storage::delete("gone").await;
+            let seen = cyb::storage::get("seen").await.unwrap();
+            format!("{}|{:?}", seen.len(), cyb::storage::list().await)
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::result(&result).contains("2|"), "{}", result);
+    assert_eq!(host.storage("test").len(), 1);
+    assert_eq!(host.storage("test")["seen"], json!(["QmA", "QmB"]));
+}
+
+#[test]
+fn refuses_keys_over_quota() {
+    let host = host(1000);
+
+    let script = r#"
+        pub async fn main() {
+            cyb::storage::set("key0", "replaced").await;
+            cyb::storage::set("new", 1).await;
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::error(&result).contains("at most 1000 keys"));
+    assert_eq!(host.storage("test")["key0"], json!("replaced"));
+    assert!(!host.storage("test").contains_key("new"));
+}
+
+#[test]
+fn counts_concurrent_writes_against_quota() {
+    let host = host(999);
+
+    let script = r#"
+        pub async fn main() {
+            std::future::join((cyb::storage::set("a", 1), cyb::storage::set("b", 2))).await;
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::error(&result).contains("at most 1000 keys"));
+    assert_eq!(host.storage("test").len(), 1000);
+}
+
+#[test]
+fn frees_quota_on_delete() {
+    let host = host(1000);
+
+    let script = r#"
+        pub async fn main() {
+            cyb::storage::delete("key0").await;
+            cyb::storage::set("new", 1).await;
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(result["error"].is_null(), "{}", result);
+    assert!(host.storage("test").contains_key("new"));
+}
+
+#[test]
+fn refuses_oversized_keys_and_values() {
+    let host = host(0);
+
+    let result = common::run(&host, r#"pub async fn main() { cyb::storage::set("", 1).await }"#);
+    assert!(common::error(&result).contains("1 to 256 bytes"));
+
+    let script = r#"
+        pub async fn main() {
+            let value = String::new();
+
+            for _ in 0..70000 {
+                value.push('a');
+            }
+
+            cyb::storage::set("big", value).await
+        }
+    "#;
+
+    let result = common::run(&host, script);
+    assert!(common::error(&result).contains("at most 65536 are allowed"));
+}
+
+#[test]
+fn needs_script_id() {
+    let host = host(0);
+    let mut compiler_params = common::compiler_params(json!({}));
+    compiler_params["scriptId"] = SerdeValue::Null;
+
+    let script = r#"pub async fn main() { cyb::storage::get("key").await }"#;
+    let result = common::run_with(&host, script, json!({}), compiler_params);
+    assert!(common::error(&result).contains("set `scriptId`"));
+}
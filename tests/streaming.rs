@@ -0,0 +1,52 @@
+mod common;
+
+use serde_json::json;
+
+const SCRIPT: &str = r#"
+pub async fn main() {
+    let stream = cyb::llm::stream([#{ role: "user", content: "hello" }], #{}).await;
+    let chunks = 0;
+
+    while let Some(token) = stream.next().await {
+        print!("{}", token);
+        chunks += 1;
+    }
+
+    chunks
+}
+"#;
+
+#[test]
+fn streams_chunks_as_output() {
+    let host = common::host(|host| {
+        host.add_prompt("hello", "one two three");
+        host.stream_output();
+    });
+
+    let result = common::run(&host, SCRIPT);
+    assert!(common::result(&result).contains('3'), "{}", result);
+    assert_eq!(host.streamed_output().as_deref(), Some("one two three"));
+}
+
+#[test]
+fn collects_output_without_streaming() {
+    let host = common::host(|host| host.add_prompt("hello", "one two three"));
+
+    let result = common::run(&host, SCRIPT);
+    assert_eq!(result["output"], "one two three");
+    assert_eq!(host.streamed_output(), None);
+}
+
+#[test]
+fn replays_recorded_chunks() {
+    let host = common::host(|host| host.add_prompt("hello", "one two three"));
+
+    let recorded = common::run_with(&host, SCRIPT, json!({}), common::compiler_params(json!({ "record": true })));
+    assert!(common::result(&recorded).contains('3'));
+
+    let replaying = common::host(|_| {});
+    let config = json!({ "replay": recorded["replay"] });
+    let replayed = common::run_with(&replaying, SCRIPT, json!({}), common::compiler_params(config));
+    assert!(common::result(&replayed).contains('3'));
+    assert_eq!(replayed["output"], "one two three");
+}